@@ -0,0 +1,235 @@
+use crate::{db::migrate_arweave_owner_addresses, metrics::METRICS};
+use anyhow::{Context, Error};
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool};
+use std::{
+    collections::BTreeSet,
+    fmt,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tracing::{error, info};
+
+/// How owner addresses are admitted: everyone, only allowlisted owners, or everyone but
+/// denylisted owners.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessMode {
+    Open,
+    Allowlist,
+    Denylist,
+}
+
+impl AccessMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Open => "open",
+            Self::Allowlist => "allowlist",
+            Self::Denylist => "denylist",
+        }
+    }
+
+    fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "open" => Some(Self::Open),
+            "allowlist" => Some(Self::Allowlist),
+            "denylist" => Some(Self::Denylist),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OwnerRule {
+    Allow,
+    Deny,
+}
+
+impl OwnerRule {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Allow => "allow",
+            Self::Deny => "deny",
+        }
+    }
+}
+
+/// Returned (wrapped in an [`anyhow::Error`]) when the access policy refuses a DataItem owner.
+#[derive(Debug)]
+pub struct OwnerNotAllowed(pub String);
+
+impl fmt::Display for OwnerNotAllowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "owner {} is not allowed to upload", self.0)
+    }
+}
+
+impl std::error::Error for OwnerNotAllowed {}
+
+/// The policy as last loaded from SQLite.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessSnapshot {
+    pub mode: AccessMode,
+    pub allow: BTreeSet<String>,
+    pub deny: BTreeSet<String>,
+}
+
+impl AccessSnapshot {
+    fn admits(&self, owner: &str) -> bool {
+        match self.mode {
+            AccessMode::Open => true,
+            AccessMode::Allowlist => self.allow.contains(owner),
+            AccessMode::Denylist => !self.deny.contains(owner),
+        }
+    }
+}
+
+/// Owner access policy kept in SQLite and cached in memory. Admin changes reload the cache
+/// right away, a background refresh picks up changes made by other instances or by hand.
+pub struct AccessPolicy {
+    pool: SqlitePool,
+    snapshot: RwLock<AccessSnapshot>,
+}
+
+impl AccessPolicy {
+    pub async fn load(pool: SqlitePool) -> Result<Self, Error> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS access_settings (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                mode TEXT NOT NULL
+            )
+        "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS access_owners (
+                owner_address TEXT PRIMARY KEY,
+                rule TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            )
+        "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        migrate_arweave_owner_addresses(&pool, "access_owners", "owner_address").await?;
+
+        let snapshot = read_snapshot(&pool).await?;
+        Ok(Self { pool, snapshot: RwLock::new(snapshot) })
+    }
+
+    pub fn snapshot(&self) -> AccessSnapshot {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    /// Fails with [`OwnerNotAllowed`] unless the owner address may upload.
+    pub fn check(&self, owner: &str) -> Result<(), Error> {
+        if self.snapshot.read().unwrap().admits(owner) {
+            return Ok(());
+        }
+        Err(OwnerNotAllowed(owner.to_string()).into())
+    }
+
+    pub async fn reload(&self) -> Result<AccessSnapshot, Error> {
+        let snapshot = read_snapshot(&self.pool).await?;
+        *self.snapshot.write().unwrap() = snapshot.clone();
+        Ok(snapshot)
+    }
+
+    pub async fn set_mode(&self, mode: AccessMode) -> Result<AccessSnapshot, Error> {
+        let timer = METRICS.backend("sqlite", "set_access_mode").start_timer();
+        sqlx::query(
+            "INSERT INTO access_settings (id, mode) VALUES (1, ?) \
+             ON CONFLICT(id) DO UPDATE SET mode = excluded.mode",
+        )
+        .bind(mode.as_str())
+        .execute(&self.pool)
+        .await?;
+        timer.observe_duration();
+
+        info!(mode = mode.as_str(), "access mode changed");
+        self.reload().await
+    }
+
+    pub async fn set_owner_rule(
+        &self,
+        owner: &str,
+        rule: OwnerRule,
+    ) -> Result<AccessSnapshot, Error> {
+        let timer = METRICS.backend("sqlite", "set_owner_rule").start_timer();
+        sqlx::query(
+            "INSERT OR REPLACE INTO access_owners (owner_address, rule, updated_at) VALUES (?, ?, ?)",
+        )
+        .bind(owner)
+        .bind(rule.as_str())
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&self.pool)
+        .await?;
+        timer.observe_duration();
+
+        info!(owner, rule = rule.as_str(), "owner access rule set");
+        self.reload().await
+    }
+
+    pub async fn remove_owner(&self, owner: &str) -> Result<AccessSnapshot, Error> {
+        let timer = METRICS.backend("sqlite", "remove_owner_rule").start_timer();
+        sqlx::query("DELETE FROM access_owners WHERE owner_address = ?")
+            .bind(owner)
+            .execute(&self.pool)
+            .await?;
+        timer.observe_duration();
+
+        info!(owner, "owner access rule removed");
+        self.reload().await
+    }
+
+    /// Reload the policy every `period` for as long as the process runs.
+    pub fn spawn_refresh(self: &Arc<Self>, period: Duration) {
+        let policy = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(period);
+            // the first tick completes immediately, the policy was just loaded
+            tick.tick().await;
+            loop {
+                tick.tick().await;
+                if let Err(e) = policy.reload().await {
+                    error!(error = ?e, "reloading access policy failed");
+                }
+            }
+        });
+    }
+}
+
+async fn read_snapshot(pool: &SqlitePool) -> Result<AccessSnapshot, Error> {
+    let _timer = METRICS.backend("sqlite", "read_access_policy").start_timer();
+
+    let mode =
+        match sqlx::query_scalar::<_, String>("SELECT mode FROM access_settings WHERE id = 1")
+            .fetch_optional(pool)
+            .await?
+        {
+            Some(raw) => {
+                AccessMode::parse(&raw).with_context(|| format!("unknown access mode {raw}"))?
+            }
+            // nothing configured yet
+            None => AccessMode::Open,
+        };
+
+    let mut allow = BTreeSet::new();
+    let mut deny = BTreeSet::new();
+    for row in sqlx::query("SELECT owner_address, rule FROM access_owners").fetch_all(pool).await? {
+        let owner: String = row.get("owner_address");
+        match row.get::<String, _>("rule").as_str() {
+            "allow" => allow.insert(owner),
+            "deny" => deny.insert(owner),
+            other => return Err(anyhow::anyhow!("unknown access rule {other} for {owner}")),
+        };
+    }
+
+    Ok(AccessSnapshot { mode, allow, deny })
+}
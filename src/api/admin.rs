@@ -0,0 +1,133 @@
+use crate::{
+    access::{AccessMode, AccessPolicy, AccessSnapshot, OwnerRule},
+    config::Config,
+    quota::{OwnerQuota, OwnerUsage, Quotas},
+};
+use axum::{
+    Json,
+    extract::{Path, Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tracing::{error, instrument, warn};
+
+#[derive(Debug, Deserialize)]
+pub struct SetModeRequest {
+    pub mode: AccessMode,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetOwnerRuleRequest {
+    pub rule: OwnerRule,
+}
+
+/// Rejects admin requests without `Authorization: Bearer <admin_token>`. Tokens are compared
+/// as SHA-256 digests in constant time, so neither their content nor length leaks through
+/// timing.
+pub async fn require_admin_token(
+    State(config): State<Arc<Config>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let expected = config.admin_token.as_deref().ok_or(StatusCode::NOT_FOUND)?;
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    let matches = provided.is_some_and(|provided| {
+        bool::from(Sha256::digest(provided).ct_eq(&Sha256::digest(expected)))
+    });
+    if !matches {
+        warn!("admin request with a missing or wrong token");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(next.run(request).await)
+}
+
+pub async fn get_access_policy_handler(
+    State(access): State<Arc<AccessPolicy>>,
+) -> Json<AccessSnapshot> {
+    Json(access.snapshot())
+}
+
+#[instrument(skip_all, fields(mode = ?body.mode))]
+pub async fn set_access_mode_handler(
+    State(access): State<Arc<AccessPolicy>>,
+    Json(body): Json<SetModeRequest>,
+) -> Result<Json<AccessSnapshot>, StatusCode> {
+    access.set_mode(body.mode).await.map(Json).map_err(|e| {
+        error!(error = ?e, "setting access mode failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[instrument(skip_all, fields(owner = %owner, rule = ?body.rule))]
+pub async fn set_owner_rule_handler(
+    Path(owner): Path<String>,
+    State(access): State<Arc<AccessPolicy>>,
+    Json(body): Json<SetOwnerRuleRequest>,
+) -> Result<Json<AccessSnapshot>, StatusCode> {
+    access.set_owner_rule(&owner, body.rule).await.map(Json).map_err(|e| {
+        error!(error = ?e, "setting owner rule failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[instrument(skip_all, fields(owner = %owner))]
+pub async fn remove_owner_rule_handler(
+    Path(owner): Path<String>,
+    State(access): State<Arc<AccessPolicy>>,
+) -> Result<Json<AccessSnapshot>, StatusCode> {
+    access.remove_owner(&owner).await.map(Json).map_err(|e| {
+        error!(error = ?e, "removing owner rule failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[instrument(skip_all, fields(owner = %owner))]
+pub async fn set_owner_quota_handler(
+    Path(owner): Path<String>,
+    State(quotas): State<Arc<Quotas>>,
+    Json(quota): Json<OwnerQuota>,
+) -> Result<Json<OwnerUsage>, StatusCode> {
+    let updated = async {
+        quotas.set_quota(&owner, quota).await?;
+        quotas.usage(&owner).await
+    };
+    updated.await.map(Json).map_err(|e| {
+        error!(error = ?e, "setting owner quota failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[instrument(skip_all, fields(owner = %owner))]
+pub async fn remove_owner_quota_handler(
+    Path(owner): Path<String>,
+    State(quotas): State<Arc<Quotas>>,
+) -> Result<Json<OwnerUsage>, StatusCode> {
+    let updated = async {
+        quotas.remove_quota(&owner).await?;
+        quotas.usage(&owner).await
+    };
+    updated.await.map(Json).map_err(|e| {
+        error!(error = ?e, "removing owner quota failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+pub async fn reload_access_policy_handler(
+    State(access): State<Arc<AccessPolicy>>,
+) -> Result<Json<AccessSnapshot>, StatusCode> {
+    access.reload().await.map(Json).map_err(|e| {
+        error!(error = ?e, "reloading access policy failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
@@ -0,0 +1,15 @@
+use crate::graphql::GatewaySchema;
+use async_graphql::http::GraphiQLSource;
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::{extract::State, response::Html};
+
+pub async fn graphql_handler(
+    State(schema): State<GatewaySchema>,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    schema.execute(request.into_inner()).await.into()
+}
+
+pub async fn graphiql_handler() -> Html<String> {
+    Html(GraphiQLSource::build().endpoint("/graphql").finish())
+}
@@ -1,46 +1,56 @@
 use crate::{
-    api::interfaces::{DataItemStatus, Info},
-    s3::{does_dataitem_exist, store_signed_dataitem},
-    utils::{
-        DATA_CACHES, FAST_FINALITY_INDEXES, FREE_UPLOAD_LIMIT_BYTES, OBJECT_SIZE_LIMIT,
-        RECEIPT_HEIGHT_DEADLINE, RECEIPT_VERSION, UPLOADER_AR_ADDRESS, extract_owner_address,
-        reconstruct_dataitem_data,
+    access::OwnerNotAllowed,
+    api::{
+        interfaces::{DataItemStatus, Info},
+        receipts::issue_receipt,
     },
+    config::Config,
+    metrics::METRICS,
+    quota::{DataItemTooLarge, InsufficientBalance, OwnerUsage, Quotas},
+    state::AppState,
+    storage::{StorageBackend, does_dataitem_exist, store_dataitem_stream},
 };
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
 
-use crate::arbundles::{SignedReceipt, UnsignedReceipt, sign_receipt};
+use crate::arbundles::{
+    SignedReceipt,
+    token::Token,
+    verify::{InvalidDataItem, signature_type_name},
+};
 use axum::{
     Json,
-    body::Bytes,
-    extract::Path,
-    http::{HeaderMap, StatusCode},
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
 };
+use prometheus::TEXT_FORMAT;
 use serde_json::Value;
+use tracing::{Span, error, field::Empty, instrument, warn};
 
-pub async fn handle_load_info() -> Json<Value> {
+pub async fn handle_load_info(State(config): State<Arc<Config>>) -> Json<Value> {
     Json(serde_json::json!({
         "status": "running",
         "name": "loaded-turbo-api",
         "version": env!("CARGO_PKG_VERSION"),
-        "object_size_limit": OBJECT_SIZE_LIMIT,
-        "data_caches": vec![DATA_CACHES.to_string()],
-        "fast_finality_indexes": vec![FAST_FINALITY_INDEXES.to_string()]
+        "object_size_limit": config.object_size_limit,
+        "data_caches": config.data_caches,
+        "fast_finality_indexes": config.fast_finality_indexes
     }))
 }
 
-pub async fn handle_info() -> Json<Value> {
+pub async fn handle_info(State(config): State<Arc<Config>>) -> Json<Value> {
     let res = Info {
         version: env!("CARGO_PKG_VERSION").to_string(),
-        gateway: DATA_CACHES.to_string(),
-        free_upload_limit_bytes: FREE_UPLOAD_LIMIT_BYTES,
-        addresses: vec![UPLOADER_AR_ADDRESS.to_string()],
+        gateway: config.data_caches[0].clone(),
+        free_upload_limit_bytes: config.free_upload_limit_bytes,
+        addresses: vec![config.uploader_ar_address.clone()],
     };
     Json(serde_json::to_value(res).unwrap())
 }
 
-pub async fn handle_bundler_metrics() -> &'static str {
-    "# ALL BUENO"
+pub async fn handle_bundler_metrics() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, TEXT_FORMAT)], METRICS.render())
 }
 
 pub async fn handle_health() -> &'static str {
@@ -51,10 +61,23 @@ pub async fn handle_tx_offsets(Path(_id): Path<String>) -> &'static str {
     "Method not supported: Load Bundler does not bundle dataitems, dataitems get bundled by the Arweave permanent bundling service (e.g. Turbo)"
 }
 
+#[instrument(skip_all, fields(owner = %owner))]
+pub async fn handle_owner_usage(
+    Path(owner): Path<String>,
+    State(quotas): State<Arc<Quotas>>,
+) -> Result<Json<OwnerUsage>, StatusCode> {
+    quotas.usage(&owner).await.map(Json).map_err(|e| {
+        error!(error = ?e, "loading owner usage failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[instrument(skip_all, fields(dataitem_id = %dataitem_id))]
 pub async fn handle_dataitem_status(
     Path(dataitem_id): Path<String>,
+    State(storage): State<Arc<dyn StorageBackend>>,
 ) -> Result<Json<Value>, StatusCode> {
-    if does_dataitem_exist(&dataitem_id).await.unwrap_or_default() {
+    if does_dataitem_exist(storage.as_ref(), &dataitem_id).await.unwrap_or_default() {
         let res = DataItemStatus {
             status: "CONFIRMED".to_string(),
             bundle_id: None,
@@ -69,52 +92,78 @@ pub async fn handle_dataitem_status(
     }
 }
 
+/// Status, metrics outcome and client message for a failed store or finalize.
+pub(crate) fn upload_error_response(
+    e: &anyhow::Error,
+    internal_message: &str,
+) -> (StatusCode, &'static str, String) {
+    if let Some(invalid) = e.downcast_ref::<InvalidDataItem>() {
+        return (StatusCode::BAD_REQUEST, "invalid", invalid.to_string());
+    }
+    if let Some(denied) = e.downcast_ref::<OwnerNotAllowed>() {
+        return (StatusCode::FORBIDDEN, "denied", denied.to_string());
+    }
+    if let Some(over_quota) = e.downcast_ref::<InsufficientBalance>() {
+        return (StatusCode::PAYMENT_REQUIRED, "over_quota", over_quota.to_string());
+    }
+    if let Some(too_large) = e.downcast_ref::<DataItemTooLarge>() {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "too_large", too_large.to_string());
+    }
+    (StatusCode::INTERNAL_SERVER_ERROR, "error", internal_message.to_string())
+}
+
+#[instrument(skip_all, fields(token = %token, dataitem_id = Empty))]
 pub async fn upload_tx_handler(
-    Path(_token): Path<String>,
+    Path(token): Path<Token>,
+    State(state): State<AppState>,
     headers: HeaderMap,
-    body: Bytes,
-) -> Result<Json<SignedReceipt>, StatusCode> {
+    body: Body,
+) -> Result<Json<SignedReceipt>, (StatusCode, String)> {
     if let Some(content_type) = headers.get("content-type") {
         if content_type != "application/octet-stream" {
-            println!("upload_tx_handler: invalid content-type {:?}", content_type);
-            return Err(StatusCode::BAD_REQUEST);
+            warn!(?content_type, "invalid content-type");
+            return Err((StatusCode::BAD_REQUEST, "Invalid content-type".to_string()));
         }
     }
 
-    let data = body.to_vec();
+    let declared_size = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
 
-    let (dataitem, _content_type) = match reconstruct_dataitem_data(data.clone()) {
-        Ok(result) => result,
+    let stored = match store_dataitem_stream(&state, token, declared_size, body.into_data_stream())
+        .await
+    {
+        Ok(stored) => stored,
         Err(e) => {
-            println!("upload_tx_handler: reconstruct failed error={e:?}");
-            return Err(StatusCode::BAD_REQUEST);
+            error!(error = ?e, "storing dataitem failed");
+            let (status, outcome, message) = upload_error_response(&e, "Failed to store dataitem");
+            METRICS.record_upload("tx", token, "unknown", outcome, 0);
+            return Err((status, message));
         }
     };
 
-    let transaction_id = match store_signed_dataitem(data).await {
-        Ok(id) => id,
-        Err(e) => {
-            println!("upload_tx_handler: store failed error={e:?}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    let owner = extract_owner_address(&dataitem);
-
-    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-
-    let unsigned_receipt = UnsignedReceipt {
-        id: transaction_id,
-        owner,
-        data_caches: vec![DATA_CACHES.to_string()],
-        fast_finality_indexes: vec![FAST_FINALITY_INDEXES.to_string()],
-        winc: "0".to_string(),
-        version: RECEIPT_VERSION.to_string(),
-        deadline_height: RECEIPT_HEIGHT_DEADLINE,
-        timestamp,
-    };
-
-    let signed_receipt: SignedReceipt = sign_receipt(unsigned_receipt).unwrap();
+    Span::current().record("dataitem_id", stored.id.as_str());
+    METRICS.record_upload(
+        "tx",
+        token,
+        signature_type_name(stored.signature_type),
+        "ok",
+        stored.size,
+    );
+
+    let signed_receipt = issue_receipt(
+        &state.db_pool,
+        &state.config,
+        &state.receipt_signer,
+        stored.id,
+        stored.owner,
+    )
+    .await
+    .map_err(|e| {
+        error!(error = ?e, "issuing receipt failed");
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to sign receipt".to_string())
+    })?;
 
     Ok(Json(signed_receipt))
 }
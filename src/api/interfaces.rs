@@ -22,3 +22,55 @@ pub struct DataItemStatus {
     pub winc: String,
     pub reason: Option<String>,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataItemTag {
+    pub name: String,
+    pub value: String,
+}
+
+/// Parsed ANS-104 header of a stored dataitem, binary fields base64url encoded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataItemMetadata {
+    pub id: String,
+    pub signature_type: u16,
+    pub signature_type_name: String,
+    pub signature: String,
+    // raw owner public key
+    pub owner: String,
+    // address derived from the owner for its signature type
+    pub owner_address: String,
+    pub target: Option<String>,
+    pub anchor: Option<String>,
+    pub tags: Vec<DataItemTag>,
+    pub data_size: u64,
+    pub data_offset: u64,
+}
+
+/// One indexed dataitem in a search or owner listing page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataItemSummary {
+    pub id: String,
+    pub size: Option<u64>,
+    pub content_type: String,
+    // ms since the epoch
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataItemPage {
+    pub items: Vec<DataItemSummary>,
+    // pass back as `cursor` for the next page, none on the last one
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptVerification {
+    // signature checks out and was made with this bundler's key
+    pub valid: bool,
+    pub reason: Option<String>,
+}
@@ -1,3 +1,8 @@
+pub mod admin;
+pub mod graphql;
 pub mod handlers;
 pub mod interfaces;
 pub mod multipart_uploads;
+pub mod receipts;
+pub mod retrieval;
+pub mod search;
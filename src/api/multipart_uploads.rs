@@ -1,23 +1,29 @@
 use crate::{
-    arbundles::{SignedReceipt, UnsignedReceipt, sign_receipt},
+    api::{handlers::upload_error_response, receipts::issue_receipt},
+    arbundles::{ReceiptSigner, SignedReceipt, token::Token},
+    config::Config,
     db::{
-        create_upload_record, get_chunks, get_completed_upload, get_upload, save_chunk,
-        update_chunk_size,
-    },
-    s3::{create_s3_multipart, finalize_multipart_upload, upload_part_s3},
-    utils::{
-        CHUNK_MAX_SIZE, CHUNK_MIN_SIZE, DATA_CACHES, DEFAULT_CHUNK_SIZE, FAST_FINALITY_INDEXES,
-        RECEIPT_HEIGHT_DEADLINE, RECEIPT_VERSION,
+        ChunkInfo, UPLOAD_FAILED, create_upload_record, fail_upload, get_chunks,
+        get_completed_upload, get_upload, request_finalize, save_chunk, set_upload_owner,
     },
+    finalizer::FinalizeJob,
+    metrics::METRICS,
+    quota::Quotas,
+    state::AppState,
+    storage::{StorageBackend, UploadNotFound},
+    utils::{DEFAULT_CHUNK_SIZE, is_unexpected_eof, parse_dataitem_header},
 };
 use axum::{
     Json,
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
 use chrono;
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
+use std::{io::Cursor, sync::Arc};
+use tracing::{Span, error, field::Empty, instrument, warn};
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,53 +37,91 @@ pub struct GetUploadResponse {
     pub failed_reason: Option<String>,
 }
 
+// set by clients that pick their chunk size through a header rather than `?chunkSize=`
+const CHUNK_SIZE_HEADER: &str = "x-chunk-size";
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUploadParams {
+    #[serde(rename = "chunkSize")]
+    pub chunk_size: Option<usize>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MultipartUploadStatus {
     pub status: String,
     pub receipt: SignedReceipt,
 }
 
+/// Open an upload session. Its chunk size is fixed here, from `?chunkSize=` or the
+/// `x-chunk-size` header, and every chunk posted to the session is checked against it.
+#[instrument(skip_all, fields(token = %token, upload_id = Empty, chunk_size = Empty))]
 pub async fn create_multipart_upload_handler(
-    Path(_token): Path<String>,
+    Path(token): Path<Token>,
+    Query(params): Query<CreateUploadParams>,
     State(pool): State<SqlitePool>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
+    let header = headers.get(CHUNK_SIZE_HEADER).map(|value| value.to_str().unwrap_or_default());
+    let chunk_size = match session_chunk_size(
+        params.chunk_size,
+        header,
+        config.chunk_min_size,
+        config.chunk_max_size,
+    ) {
+        Ok(chunk_size) => chunk_size,
+        Err(reason) => {
+            warn!(query = params.chunk_size, header, reason, "invalid chunk size");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+
     let upload_id = Uuid::new_v4().to_string();
     let upload_key = format!("multipart-{}", Uuid::new_v4());
+    Span::current().record("upload_id", upload_id.as_str());
+    Span::current().record("chunk_size", chunk_size);
 
-    let s3_upload_id = match create_s3_multipart(&upload_key).await {
+    let s3_upload_id = match storage.create_multipart(&upload_key, None).await {
         Ok(id) => id,
         Err(e) => {
-            println!("create_multipart_upload: s3 create failed upload_id={upload_id} error={e:?}");
+            error!(error = ?e, "creating multipart upload in storage failed");
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
 
     // store in db
-    if let Err(e) = create_upload_record(&pool, &upload_id, &upload_key, &s3_upload_id).await {
-        println!("create_multipart_upload: db insert failed upload_id={upload_id} error={e:?}");
+    if let Err(e) =
+        create_upload_record(&pool, &upload_id, &upload_key, &s3_upload_id, chunk_size as i64).await
+    {
+        error!(error = ?e, "storing upload record failed");
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     }
 
+    METRICS.multipart_sessions_total.with_label_values(&["created"]).inc();
+
     // return the format Turbo-sdk expects to progress to upload phase
     let response = serde_json::json!({
         "id": upload_id,
-        "max": CHUNK_MAX_SIZE,
-        "min": CHUNK_MIN_SIZE,
-        "size": CHUNK_MAX_SIZE,
+        "max": config.chunk_max_size,
+        "min": config.chunk_min_size,
+        "size": chunk_size,
         "chunks": []
     });
 
     Ok(Json(response))
 }
 
+#[instrument(skip_all, fields(token = %token, upload_id = %upload_id))]
 pub async fn get_multipart_upload_handler(
-    Path((_token, upload_id)): Path<(String, String)>,
+    Path((token, upload_id)): Path<(Token, String)>,
     State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
 ) -> Result<Json<GetUploadResponse>, StatusCode> {
     let upload = match get_upload(&pool, &upload_id).await {
         Ok(upload) => upload,
         Err(e) => {
-            println!("get_multipart_upload: not found upload_id={upload_id} error={e:?}");
+            warn!(error = ?e, "upload not found");
             return Err(StatusCode::NOT_FOUND);
         }
     };
@@ -85,7 +129,7 @@ pub async fn get_multipart_upload_handler(
     let chunks = match get_chunks(&pool, &upload_id).await {
         Ok(chunks) => chunks,
         Err(e) => {
-            println!("get_multipart_upload: chunks failed upload_id={upload_id} error={e:?}");
+            error!(error = ?e, "loading chunks failed");
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
@@ -103,17 +147,78 @@ pub async fn get_multipart_upload_handler(
 
     Ok(Json(GetUploadResponse {
         id: upload.upload_id,
-        max: CHUNK_MAX_SIZE,
-        min: CHUNK_MIN_SIZE,
+        max: config.chunk_max_size,
+        min: config.chunk_min_size,
         size: chunk_size,
         chunks: chunk_offsets,
         failed_reason: upload.failed_reason,
     }))
 }
 
+/// The chunk size a session is opened with: `?chunkSize=` over the `x-chunk-size` header,
+/// or the default clamped into `min..=max` when neither is set. Fails with the reason for a
+/// header that is not a number or a size out of bounds.
+fn session_chunk_size(
+    query: Option<usize>,
+    header: Option<&str>,
+    min: usize,
+    max: usize,
+) -> Result<usize, &'static str> {
+    let requested = match (query, header) {
+        (Some(chunk_size), _) => Some(chunk_size),
+        (None, Some(header)) => {
+            Some(header.parse::<usize>().map_err(|_| "chunk size header is not a number")?)
+        }
+        (None, None) => None,
+    };
+    let chunk_size = requested.unwrap_or_else(|| (DEFAULT_CHUNK_SIZE as usize).clamp(min, max));
+    if chunk_size < min || chunk_size > max {
+        return Err("chunk size out of bounds");
+    }
+    Ok(chunk_size)
+}
+
+/// The part number of a chunk of `length` bytes posted at `offset` to a session of
+/// `chunk_size` chunks already holding `chunks`, following Turbo: offsets are aligned to the
+/// chunk size and only the final chunk may be shorter. Fails with the reason it is refused.
+fn chunk_part_number(
+    chunk_size: usize,
+    offset: usize,
+    length: usize,
+    chunks: &[ChunkInfo],
+) -> Result<usize, &'static str> {
+    if !offset.is_multiple_of(chunk_size) {
+        return Err("offset not aligned to the chunk size");
+    }
+    if length > chunk_size {
+        return Err("chunk larger than the chunk size");
+    }
+
+    let part_number = (offset / chunk_size) + 1;
+    if part_number > 10_000 {
+        return Err("part number too large");
+    }
+
+    // a short chunk can't sit before another one, whichever of the two was posted first
+    let part = part_number as i64;
+    let is_short = length < chunk_size;
+    let misplaced = chunks.iter().filter(|chunk| chunk.part_number != part).any(|chunk| {
+        let other_short = (chunk.size as usize) < chunk_size;
+        (other_short && (is_short || chunk.part_number < part))
+            || (is_short && chunk.part_number > part)
+    });
+    if misplaced {
+        return Err("only the final chunk may be shorter than the chunk size");
+    }
+    Ok(part_number)
+}
+
+#[instrument(skip_all, fields(token = %token, upload_id = %upload_id, offset = chunk_offset))]
 pub async fn post_chunk_handler(
-    Path((_token, upload_id, chunk_offset)): Path<(String, String, usize)>,
+    Path((token, upload_id, chunk_offset)): Path<(Token, String, usize)>,
     State(pool): State<SqlitePool>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(quotas): State<Arc<Quotas>>,
     headers: HeaderMap,
     body: axum::body::Bytes,
 ) -> Result<StatusCode, StatusCode> {
@@ -122,68 +227,88 @@ pub async fn post_chunk_handler(
         .and_then(|v| v.to_str().ok())
         .and_then(|v| v.parse::<usize>().ok())
         .ok_or_else(|| {
-            println!(
-                "post_chunk: missing content-length upload_id={upload_id} offset={chunk_offset}"
-            );
+            warn!("missing content-length");
             StatusCode::BAD_REQUEST
         })?;
 
     let upload = match get_upload(&pool, &upload_id).await {
         Ok(upload) => upload,
         Err(e) => {
-            println!("post_chunk: upload not found upload_id={upload_id} error={e:?}");
+            warn!(error = ?e, "upload not found");
             return Err(StatusCode::NOT_FOUND);
         }
     };
 
     if upload.failed_reason.is_some() {
-        println!("post_chunk: upload failed upload_id={upload_id}");
+        warn!(failed_reason = upload.failed_reason, "chunk posted to a failed upload");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if upload.finalize_requested_at.is_some() {
+        warn!(status = upload.status, "chunk posted to an upload being finalized");
         return Err(StatusCode::BAD_REQUEST);
     }
 
-    let chunk_size = if upload.chunk_size.is_none()
-        || content_length as i64 > upload.chunk_size.unwrap_or(0)
-    {
-        match update_chunk_size(&pool, &upload_id, content_length as i64).await {
-            Ok(_) => content_length,
-            Err(_e) => {
-                println!(
-                    "post_chunk: update chunk size failed upload_id={upload_id} size={content_length}"
-                );
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    // sessions opened before the chunk size was fixed at creation use the default
+    let chunk_size = upload.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE) as usize;
+
+    let chunks = match get_chunks(&pool, &upload_id).await {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            error!(error = ?e, "loading chunks failed");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let part_number = match chunk_part_number(chunk_size, chunk_offset, content_length, &chunks) {
+        Ok(part_number) => part_number,
+        Err(reason) => {
+            warn!(size = chunk_size, length = content_length, reason, "chunk refused");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+    let part = part_number as i64;
+
+    // the first chunk starts with the dataitem header, which names the owner
+    let owner_address = if part_number == 1 {
+        match parse_dataitem_header(&mut Cursor::new(&body[..])) {
+            Ok(header) => {
+                let owner_address = header.owner_address();
+                if let Err(e) = set_upload_owner(&pool, &upload_id, &owner_address).await {
+                    error!(error = ?e, "storing upload owner failed");
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+                Some(owner_address)
+            }
+            // a header longer than the chunk is left to the check at finalize
+            Err(e) if is_unexpected_eof(&e) => None,
+            Err(e) => {
+                warn!(error = ?e, "invalid dataitem header in the first chunk");
+                return Err(StatusCode::BAD_REQUEST);
             }
         }
     } else {
-        upload.chunk_size.unwrap() as usize
+        upload.owner_address
     };
 
-    // validate Turbo standards alignment
-    if chunk_offset % chunk_size != 0 {
-        println!(
-            "post_chunk: misaligned offset upload_id={upload_id} offset={chunk_offset} size={chunk_size}"
-        );
-        return Err(StatusCode::BAD_REQUEST);
-    }
-
-    let part_number = (chunk_offset / chunk_size) + 1;
-    if part_number > 10_000 {
-        println!("post_chunk: part number too large upload_id={upload_id} part={part_number}");
-        return Err(StatusCode::BAD_REQUEST);
+    // refuse chunks once the session holds more than the owner may store, a dataitem stored
+    // before is only recognized by its id at finalize
+    if let Some(owner_address) = owner_address {
+        let stored: i64 =
+            chunks.iter().filter(|chunk| chunk.part_number != part).map(|chunk| chunk.size).sum();
+        let running_size = stored.max(0) as u64 + content_length as u64;
+        if let Err(e) = quotas.check(&owner_address, None, running_size).await {
+            warn!(error = ?e, owner = owner_address, size = running_size, "chunk refused by quota");
+            let (status, _, _) = upload_error_response(&e, "Failed to check quota");
+            return Err(status);
+        }
     }
 
-    let etag = match upload_part_s3(
-        &upload.upload_key,
-        &upload.s3_upload_id,
-        part_number as i32,
-        body.to_vec(),
-    )
-    .await
+    let etag = match storage
+        .upload_part(&upload.upload_key, &upload.s3_upload_id, part_number as i32, body.to_vec())
+        .await
     {
         Ok(etag) => etag,
         Err(e) => {
-            println!(
-                "post_chunk: s3 upload failed upload_id={upload_id} part={part_number} error={e:?}"
-            );
+            error!(error = ?e, part = part_number, "uploading part to storage failed");
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
@@ -191,67 +316,151 @@ pub async fn post_chunk_handler(
     if let Err(e) =
         save_chunk(&pool, &upload_id, part_number as i64, &etag, content_length as i64).await
     {
-        println!("post_chunk: save failed upload_id={upload_id} part={part_number} error={e:?}");
+        error!(error = ?e, part = part_number, "storing chunk record failed");
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     }
 
     Ok(StatusCode::OK)
 }
 
-pub async fn finalize_multipart_upload_handler(
-    Path((_token, upload_id)): Path<(String, String)>, // Accept token parameter
+const CANCELED_REASON: &str = "upload canceled by the client";
+
+/// Abort an upload session: its stored parts are dropped and the session is marked failed,
+/// so later chunks are refused and `GET .../{upload_id}/-1` shows why.
+#[instrument(skip_all, fields(token = %token, upload_id = %upload_id))]
+pub async fn cancel_multipart_upload_handler(
+    Path((token, upload_id)): Path<(Token, String)>,
     State(pool): State<SqlitePool>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
-    match finalize_multipart_upload(&pool, &upload_id).await {
-        Ok(dataitem_id) => {
-            let unsigned_receipt = UnsignedReceipt {
-                id: dataitem_id,
-                timestamp: chrono::Utc::now().timestamp_millis() as u64,
-                version: RECEIPT_VERSION.to_string(),
-                deadline_height: RECEIPT_HEIGHT_DEADLINE,
-                data_caches: vec![DATA_CACHES.to_string()],
-                fast_finality_indexes: vec![FAST_FINALITY_INDEXES.to_string()],
-                owner: "".to_string(),
-                winc: "".to_string(),
-            };
-
-            Ok(Json(serde_json::to_value(unsigned_receipt).unwrap_or_default()))
+    State(storage): State<Arc<dyn StorageBackend>>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let upload = match get_upload(&pool, &upload_id).await {
+        Ok(upload) => upload,
+        Err(_) if get_completed_upload(&pool, &upload_id).await.is_ok() => {
+            return Err((StatusCode::CONFLICT, "Upload is already finalized".to_string()));
         }
         Err(e) => {
-            println!("finalize_multipart_upload: failed upload_id={upload_id} error={e:?}");
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            warn!(error = ?e, "upload not found");
+            return Err((StatusCode::NOT_FOUND, "Upload not found".to_string()));
+        }
+    };
+
+    // already closed, nothing is stored for it anymore
+    if upload.failed_reason.is_some() {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    if upload.finalize_requested_at.is_some() {
+        return Err((StatusCode::CONFLICT, "Upload is being finalized".to_string()));
+    }
+
+    match storage.abort_multipart(&upload.upload_key, &upload.s3_upload_id).await {
+        // gone from storage already, the session is canceled all the same
+        Err(e) if e.downcast_ref::<UploadNotFound>().is_some() => {}
+        Err(e) => {
+            error!(error = ?e, "aborting multipart upload failed");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to cancel upload".to_string()));
         }
+        Ok(()) => {}
+    }
+
+    if let Err(e) = fail_upload(&pool, &upload_id, CANCELED_REASON).await {
+        error!(error = ?e, "marking upload canceled failed");
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to cancel upload".to_string()));
+    }
+
+    METRICS.multipart_sessions_total.with_label_values(&["canceled"]).inc();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Queue an upload session for finalization. The parts are assembled and the dataitem
+/// validated in the background, the client polls `GET .../{upload_id}/status` until it reads
+/// FINALIZED with the receipt, or FAILED with the reason. Finalizing again is safe: a session
+/// in progress is resumed if it stalled, a finalized one answers with its receipt.
+#[instrument(skip_all, fields(token = %token, upload_id = %upload_id, dataitem_id = Empty))]
+pub async fn finalize_multipart_upload_handler(
+    Path((token, upload_id)): Path<(Token, String)>,
+    State(state): State<AppState>,
+) -> Result<Response, (StatusCode, String)> {
+    let internal_error =
+        || (StatusCode::INTERNAL_SERVER_ERROR, "Failed to finalize upload".to_string());
+
+    let upload = match get_upload(&state.db_pool, &upload_id).await {
+        Ok(upload) => upload,
+        Err(_) => match get_completed_upload(&state.db_pool, &upload_id).await {
+            Ok((dataitem_id, owner_address)) => {
+                Span::current().record("dataitem_id", dataitem_id.as_str());
+                let owner = owner_address.unwrap_or_else(|| "unknown".to_string());
+                let receipt = issue_receipt(
+                    &state.db_pool,
+                    &state.config,
+                    &state.receipt_signer,
+                    dataitem_id,
+                    owner,
+                )
+                .await
+                .map_err(|e| {
+                    error!(error = ?e, "issuing receipt failed");
+                    internal_error()
+                })?;
+                return Ok(Json(receipt).into_response());
+            }
+            Err(e) => {
+                warn!(error = ?e, "upload not found");
+                return Err((StatusCode::NOT_FOUND, "Upload not found".to_string()));
+            }
+        },
+    };
+
+    if let Some(failed_reason) = upload.failed_reason {
+        return Err((StatusCode::BAD_REQUEST, failed_reason));
+    }
+
+    if let Err(e) = request_finalize(&state.db_pool, &upload_id, token.as_str()).await {
+        error!(error = ?e, "recording finalize request failed");
+        return Err(internal_error());
+    }
+
+    // no-op while the session is queued or running, resumes it otherwise
+    if let Err(e) = state.finalize.enqueue(FinalizeJob::new(token, upload_id)).await {
+        error!(error = ?e, "queueing upload for finalization failed");
+        return Err(internal_error());
     }
+
+    Ok(StatusCode::ACCEPTED.into_response())
 }
 
+#[instrument(skip_all, fields(token = %token, upload_id = %upload_id, dataitem_id = Empty))]
 pub async fn get_multipart_upload_status_handler(
-    Path((_token, upload_id)): Path<(String, String)>,
+    Path((token, upload_id)): Path<(Token, String)>,
     State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    State(signer): State<Arc<ReceiptSigner>>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
-    // check if upload is still in progress
+    // still in progress or failed, as the finalize worker left it
     match get_upload(&pool, &upload_id).await {
-        Ok(_upload) => Ok(Json(serde_json::json!({
-            "status": "ASSEMBLING",
-            "timestamp": chrono::Utc::now().timestamp_millis()
-        }))),
+        Ok(upload) => {
+            let mut status = serde_json::json!({
+                "status": upload.status,
+                "timestamp": chrono::Utc::now().timestamp_millis()
+            });
+            if upload.status == UPLOAD_FAILED {
+                status["failedReason"] = serde_json::json!(upload.failed_reason);
+            }
+            Ok(Json(status))
+        }
         Err(_) => {
             // completed multipart upload
             match get_completed_upload(&pool, &upload_id).await {
                 Ok((dataitem_id, owner_address)) => {
+                    Span::current().record("dataitem_id", dataitem_id.as_str());
                     let owner = owner_address.unwrap_or_else(|| "unknown".to_string());
 
-                    let unsigned_receipt: UnsignedReceipt = UnsignedReceipt {
-                        id: dataitem_id,
-                        deadline_height: RECEIPT_HEIGHT_DEADLINE,
-                        timestamp: chrono::Utc::now().timestamp_millis() as u64,
-                        version: RECEIPT_VERSION.to_string(),
-                        owner,
-                        data_caches: vec![DATA_CACHES.to_string()],
-                        fast_finality_indexes: vec![FAST_FINALITY_INDEXES.to_string()],
-                        winc: "0".to_string(),
-                    };
-                    let signed_receipt = sign_receipt(unsigned_receipt)
-                        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                    // receipts of uploads finalized before they were persisted get issued here
+                    let signed_receipt = issue_receipt(&pool, &config, &signer, dataitem_id, owner)
+                        .await
+                        .map_err(|e| {
+                            error!(error = ?e, "issuing receipt failed");
+                            StatusCode::INTERNAL_SERVER_ERROR
+                        })?;
                     let res = MultipartUploadStatus {
                         status: "FINALIZED".to_string(),
                         receipt: signed_receipt,
@@ -259,12 +468,94 @@ pub async fn get_multipart_upload_status_handler(
                     Ok(Json(serde_json::to_value(res).unwrap_or_default()))
                 }
                 Err(e) => {
-                    println!(
-                        "get_multipart_upload_status: not found upload_id={upload_id} error={e:?}"
-                    );
+                    warn!(error = ?e, "upload not found");
                     Err(StatusCode::NOT_FOUND)
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN: usize = 5 * 1024 * 1024;
+    const MAX: usize = 500 * 1024 * 1024;
+
+    #[test]
+    fn session_chunk_sizes() {
+        let cases = [
+            // (query, header, expected)
+            (None, None, Ok(DEFAULT_CHUNK_SIZE as usize)),
+            (Some(MIN), None, Ok(MIN)),
+            (None, Some("6000000"), Ok(6_000_000)),
+            // the query wins over the header, even an invalid one
+            (Some(MAX), Some("6000000"), Ok(MAX)),
+            (Some(MIN), Some("nope"), Ok(MIN)),
+            (None, Some("nope"), Err("chunk size header is not a number")),
+            (None, Some("-1"), Err("chunk size header is not a number")),
+            (Some(MIN - 1), None, Err("chunk size out of bounds")),
+            (Some(MAX + 1), None, Err("chunk size out of bounds")),
+            (None, Some("1024"), Err("chunk size out of bounds")),
+        ];
+        for (query, header, expected) in cases {
+            assert_eq!(
+                session_chunk_size(query, header, MIN, MAX),
+                expected,
+                "{query:?} {header:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn default_chunk_size_is_clamped_into_the_bounds() {
+        let small = DEFAULT_CHUNK_SIZE as usize / 2;
+        assert_eq!(session_chunk_size(None, None, 1, small), Ok(small));
+        let large = DEFAULT_CHUNK_SIZE as usize * 2;
+        assert_eq!(session_chunk_size(None, None, large, large * 2), Ok(large));
+    }
+
+    #[test]
+    fn chunk_part_numbers() {
+        let chunk = |part_number, size| ChunkInfo { part_number, size };
+        let cases = [
+            // (offset, length, stored chunks, expected)
+            (0, 100, vec![], Ok(1)),
+            (200, 100, vec![chunk(1, 100)], Ok(3)),
+            (100, 40, vec![chunk(1, 100)], Ok(2)),
+            // a chunk posted again replaces itself
+            (0, 40, vec![chunk(1, 100)], Ok(1)),
+            (50, 100, vec![], Err("offset not aligned to the chunk size")),
+            (0, 101, vec![], Err("chunk larger than the chunk size")),
+            (100 * 10_000, 100, vec![], Err("part number too large")),
+            (100 * 9_999, 100, vec![], Ok(10_000)),
+            // short chunks before another one, posted before or after it
+            (
+                0,
+                40,
+                vec![chunk(2, 100)],
+                Err("only the final chunk may be shorter than the chunk size"),
+            ),
+            (
+                100,
+                100,
+                vec![chunk(1, 40)],
+                Err("only the final chunk may be shorter than the chunk size"),
+            ),
+            (
+                100,
+                40,
+                vec![chunk(3, 40)],
+                Err("only the final chunk may be shorter than the chunk size"),
+            ),
+        ];
+        for (offset, length, chunks, expected) in cases {
+            assert_eq!(
+                chunk_part_number(100, offset, length, &chunks),
+                expected,
+                "{offset} {length}"
+            );
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{
+    api::interfaces::ReceiptVerification,
+    arbundles::{ReceiptSigner, SignedReceipt, UnsignedReceipt, verify_receipt},
+    config::Config,
+    db::{get_receipt, store_receipt},
+};
+use anyhow::Context;
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use sqlx::SqlitePool;
+use std::sync::Arc;
+use tracing::{error, instrument};
+
+/// The receipt of a stored dataitem. It is signed the first time it is asked for and
+/// persisted, every later call (status polls, re-uploads of the same item) returns the
+/// stored one unchanged.
+pub(crate) async fn issue_receipt(
+    pool: &SqlitePool,
+    config: &Config,
+    signer: &ReceiptSigner,
+    dataitem_id: String,
+    owner: String,
+) -> anyhow::Result<SignedReceipt> {
+    if let Some(stored) = get_receipt(pool, &dataitem_id).await? {
+        return serde_json::from_str(&stored).context("stored receipt is not valid json");
+    }
+
+    let receipt = UnsignedReceipt::new(config, dataitem_id, owner);
+    let signed = signer.sign(receipt).context("receipt signing failed")?;
+    // a concurrent call may have stored its own receipt first, hand back the kept one
+    let stored = store_receipt(pool, &signed.receipt.id, &serde_json::to_string(&signed)?).await?;
+    serde_json::from_str(&stored).context("stored receipt is not valid json")
+}
+
+/// The receipt issued for a dataitem, as returned by its upload.
+#[instrument(skip_all, fields(dataitem_id = %dataitem_id))]
+pub async fn handle_dataitem_receipt(
+    Path(dataitem_id): Path<String>,
+    State(pool): State<SqlitePool>,
+) -> Result<Json<SignedReceipt>, StatusCode> {
+    match get_receipt(&pool, &dataitem_id).await {
+        Ok(Some(stored)) => serde_json::from_str(&stored).map(Json).map_err(|e| {
+            error!(error = ?e, "stored receipt is not valid json");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!(error = ?e, "loading receipt failed");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Whether a receipt was signed by this bundler and left untouched since.
+#[instrument(skip_all, fields(dataitem_id = %receipt.receipt.id))]
+pub async fn handle_verify_receipt(
+    State(signer): State<Arc<ReceiptSigner>>,
+    Json(receipt): Json<SignedReceipt>,
+) -> Json<ReceiptVerification> {
+    let reason = if receipt.public != signer.public() {
+        Some("receipt was not signed by this bundler".to_string())
+    } else {
+        verify_receipt(&receipt).err().map(|e| e.to_string())
+    };
+
+    Json(ReceiptVerification { valid: reason.is_none(), reason })
+}
@@ -0,0 +1,324 @@
+use crate::{
+    api::interfaces::{DataItemMetadata, DataItemTag},
+    arbundles::verify::signature_type_name,
+    storage::{StorageBackend, load_dataitem_header},
+};
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+use tracing::{error, instrument};
+
+// dataitems are content addressed, what is served under an id never changes
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// The part of an object a `Range` header asks for.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    // inclusive bounds, relative to the served representation
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Single `bytes=` ranges only, anything else is served in full as RFC 9110 allows.
+fn parse_range(headers: &HeaderMap, len: u64) -> ByteRange {
+    let Some(spec) = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().strip_prefix("bytes="))
+    else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    match (start.trim(), end.trim()) {
+        // suffix range, the last n bytes
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(n) => ByteRange::Partial(len.saturating_sub(n), len - 1),
+            Err(_) => ByteRange::Full,
+        },
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else {
+                return ByteRange::Full;
+            };
+            let end = match end {
+                "" => len.saturating_sub(1),
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => end.min(len.saturating_sub(1)),
+                    _ => return ByteRange::Full,
+                },
+            };
+            if start >= len { ByteRange::Unsatisfiable } else { ByteRange::Partial(start, end) }
+        }
+    }
+}
+
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()).is_some_and(|v| {
+        v.split(',').map(str::trim).any(|candidate| {
+            candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+        })
+    })
+}
+
+fn is_dataitem_id(id: &str) -> bool {
+    id.len() == 43 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// `len` bytes of the object stored under `key`, starting at `offset`.
+struct ServedObject<'a> {
+    key: &'a str,
+    offset: u64,
+    len: u64,
+    etag: String,
+    content_type: &'a str,
+}
+
+/// Serve an object honouring `If-None-Match` and `Range`. HEAD requests get the headers
+/// without reading the object.
+async fn serve_object(
+    storage: &dyn StorageBackend,
+    object: ServedObject<'_>,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Response {
+    let ServedObject { key, offset, len, etag, content_type } = object;
+    let etag_value = HeaderValue::from_str(&etag).expect("etag is a quoted dataitem id");
+    if etag_matches(headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag_value)]).into_response();
+    }
+
+    let (status, start, end) = match parse_range(headers, len) {
+        ByteRange::Full => (StatusCode::OK, 0, len.saturating_sub(1)),
+        ByteRange::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, start, end),
+        ByteRange::Unsatisfiable => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{len}"))],
+            )
+                .into_response();
+        }
+    };
+    let body_len = if len == 0 { 0 } else { end - start + 1 };
+
+    let body = if method == Method::HEAD || body_len == 0 {
+        Body::empty()
+    } else {
+        match storage.get_object_range(key, offset + start, Some(offset + end)).await {
+            Ok(stream) => Body::from_stream(stream),
+            Err(e) => {
+                error!(error = ?e, "reading object failed");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    };
+
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(content_type)
+            .unwrap_or_else(|_| HeaderValue::from_static("application/octet-stream")),
+    );
+    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body_len));
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(header::ETAG, etag_value);
+    response_headers
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static(IMMUTABLE_CACHE_CONTROL));
+    if status == StatusCode::PARTIAL_CONTENT {
+        response_headers.insert(
+            header::CONTENT_RANGE,
+            HeaderValue::from_str(&format!("bytes {start}-{end}/{len}"))
+                .expect("content-range is ascii"),
+        );
+    }
+    response
+}
+
+/// The parsed header of a stored dataitem. Only the header bytes are read from storage.
+#[instrument(skip_all, fields(dataitem_id = %dataitem_id))]
+pub async fn handle_dataitem_metadata(
+    Path(dataitem_id): Path<String>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+) -> Result<Json<DataItemMetadata>, StatusCode> {
+    if !is_dataitem_id(&dataitem_id) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let (header, object_size) = match load_dataitem_header(storage.as_ref(), &dataitem_id).await {
+        Ok(Some(found)) => found,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!(error = ?e, "reading dataitem header failed");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    Ok(Json(DataItemMetadata {
+        id: header.id(),
+        signature_type: header.signature_type,
+        signature_type_name: signature_type_name(header.signature_type).to_string(),
+        signature: header.encoded_signature(),
+        owner: header.owner_key(),
+        owner_address: header.owner_address(),
+        target: header.target_address(),
+        anchor: header.encoded_anchor(),
+        tags: header
+            .tags
+            .iter()
+            .map(|tag| DataItemTag { name: tag.name.clone(), value: tag.value.clone() })
+            .collect(),
+        data_size: object_size.saturating_sub(header.data_offset) as u64,
+        data_offset: header.data_offset as u64,
+    }))
+}
+
+/// The data payload of a stored dataitem, served with its Content-Type tag.
+#[instrument(skip_all, fields(dataitem_id = %dataitem_id))]
+pub async fn handle_dataitem_data(
+    Path(dataitem_id): Path<String>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    if !is_dataitem_id(&dataitem_id) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let (header, object_size) = match load_dataitem_header(storage.as_ref(), &dataitem_id).await {
+        Ok(Some(found)) => found,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!(error = ?e, "reading dataitem header failed");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let object = ServedObject {
+        key: &storage.dataitem_key(&dataitem_id),
+        offset: header.data_offset as u64,
+        len: object_size.saturating_sub(header.data_offset) as u64,
+        etag: format!("\"{dataitem_id}\""),
+        content_type: &header.content_type(),
+    };
+    serve_object(storage.as_ref(), object, &method, &headers).await
+}
+
+/// The full ANS-104 serialized dataitem, header included.
+#[instrument(skip_all, fields(dataitem_id = %dataitem_id))]
+pub async fn handle_dataitem_raw(
+    Path(dataitem_id): Path<String>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    if !is_dataitem_id(&dataitem_id) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let key_dataitem = storage.dataitem_key(&dataitem_id);
+    let object_size = match storage.head_object(&key_dataitem).await {
+        Ok(Some(size)) => size,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!(error = ?e, "reading dataitem size failed");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let object = ServedObject {
+        key: &key_dataitem,
+        offset: 0,
+        len: object_size,
+        // a different representation than /data, so a different etag
+        etag: format!("\"{dataitem_id}.ans104\""),
+        content_type: "application/octet-stream",
+    };
+    serve_object(storage.as_ref(), object, &method, &headers).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn range(value: &str, len: u64) -> ByteRange {
+        parse_range(&with(header::RANGE, value), len)
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse_range(&HeaderMap::new(), 100), ByteRange::Full);
+        assert_eq!(range("bytes=0-9", 100), ByteRange::Partial(0, 9));
+        assert_eq!(range("bytes=10-", 100), ByteRange::Partial(10, 99));
+        assert_eq!(range("bytes=99-99", 100), ByteRange::Partial(99, 99));
+        assert_eq!(range(" bytes= 5 - 6 ", 100), ByteRange::Partial(5, 6));
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(range("bytes=-10", 100), ByteRange::Partial(90, 99));
+        // longer than the object, the whole object
+        assert_eq!(range("bytes=-500", 100), ByteRange::Partial(0, 99));
+        assert_eq!(range("bytes=-0", 100), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn ends_past_the_length_are_clamped() {
+        assert_eq!(range("bytes=50-1000", 100), ByteRange::Partial(50, 99));
+        assert_eq!(range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=100-200", 100), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn zero_length_objects() {
+        assert_eq!(range("bytes=0-", 0), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=0-10", 0), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=-10", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn other_ranges_are_served_in_full() {
+        // multiple ranges aren't supported
+        assert_eq!(range("bytes=0-9,20-29", 100), ByteRange::Full);
+        assert_eq!(range("bytes=10-5", 100), ByteRange::Full);
+        assert_eq!(range("bytes=a-b", 100), ByteRange::Full);
+        assert_eq!(range("bytes=-x", 100), ByteRange::Full);
+        assert_eq!(range("bytes=10", 100), ByteRange::Full);
+        assert_eq!(range("items=0-9", 100), ByteRange::Full);
+    }
+
+    #[test]
+    fn if_none_match() {
+        let etag = "\"KRsvRmkwFpdCeTwmZu0Ujyz6wXAo4Ndv5Azn0oLupvU\"";
+        let matches = |value: &str| etag_matches(&with(header::IF_NONE_MATCH, value), etag);
+
+        assert!(!etag_matches(&HeaderMap::new(), etag));
+        assert!(matches(etag));
+        assert!(matches(&format!("W/{etag}")));
+        assert!(matches("*"));
+        assert!(matches(&format!("\"other\", {etag}")));
+        assert!(matches(&format!("\"other\" , W/{etag}")));
+        assert!(!matches("\"other\""));
+        assert!(!matches("W/\"other\""));
+        // unquoted tags don't match the quoted one
+        assert!(!matches(etag.trim_matches('"')));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{
+    api::interfaces::{DataItemPage, DataItemSummary},
+    indexing::{DataItemCursor, DataItemQuery, IndexDisabled, Indexer, TagFilter},
+};
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use std::sync::Arc;
+use tracing::{error, instrument};
+
+const DEFAULT_PAGE_SIZE: usize = 25;
+const MAX_PAGE_SIZE: usize = 100;
+
+/// Paging and time bounds shared by the listing endpoints, `from`/`to` in ms.
+#[derive(Debug, Default)]
+struct PageParams {
+    from: Option<i64>,
+    to: Option<i64>,
+    cursor: Option<DataItemCursor>,
+    limit: usize,
+    ascending: bool,
+}
+
+type ApiError = (StatusCode, String);
+
+fn bad_request(message: String) -> ApiError {
+    (StatusCode::BAD_REQUEST, message)
+}
+
+/// Pull the paging params out of the query string, handing back the ones left over.
+fn page_params(
+    params: Vec<(String, String)>,
+) -> Result<(PageParams, Vec<(String, String)>), ApiError> {
+    let mut page = PageParams { limit: DEFAULT_PAGE_SIZE, ..Default::default() };
+    let mut rest = Vec::new();
+    for (key, value) in params {
+        let parse_ms = |value: &str| {
+            value.parse::<i64>().map_err(|_| bad_request(format!("{key} must be a ms timestamp")))
+        };
+        match key.as_str() {
+            "from" => page.from = Some(parse_ms(&value)?),
+            "to" => page.to = Some(parse_ms(&value)?),
+            "cursor" => {
+                page.cursor =
+                    Some(DataItemCursor::decode(&value).map_err(|e| bad_request(e.to_string()))?)
+            }
+            "limit" => {
+                let limit = value
+                    .parse::<usize>()
+                    .map_err(|_| bad_request("limit must be a positive integer".to_string()))?;
+                page.limit = limit.clamp(1, MAX_PAGE_SIZE);
+            }
+            "sort" => {
+                page.ascending = match value.as_str() {
+                    "asc" => true,
+                    "desc" => false,
+                    _ => return Err(bad_request("sort must be asc or desc".to_string())),
+                }
+            }
+            _ => rest.push((key, value)),
+        }
+    }
+    Ok((page, rest))
+}
+
+async fn list_dataitems(
+    indexer: &dyn Indexer,
+    mut query: DataItemQuery,
+    page: PageParams,
+) -> Result<Json<DataItemPage>, ApiError> {
+    query.from = page.from;
+    query.to = page.to;
+    query.after = page.cursor;
+    query.ascending = page.ascending;
+    // one more than asked to tell whether there is a next page
+    query.limit = page.limit + 1;
+
+    let mut entries = indexer.query_dataitems(&query).await.map_err(|e| {
+        if e.downcast_ref::<IndexDisabled>().is_some() {
+            return (StatusCode::SERVICE_UNAVAILABLE, e.to_string());
+        }
+        error!(error = ?e, "querying the dataitem index failed");
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to query the dataitem index".to_string())
+    })?;
+
+    let next_cursor = if entries.len() > page.limit {
+        entries.truncate(page.limit);
+        entries.last().map(|entry| entry.cursor().encode())
+    } else {
+        None
+    };
+
+    Ok(Json(DataItemPage {
+        items: entries
+            .into_iter()
+            .map(|entry| DataItemSummary {
+                id: entry.id,
+                size: entry.size,
+                content_type: entry.content_type,
+                created_at: entry.created_at,
+            })
+            .collect(),
+        next_cursor,
+    }))
+}
+
+/// Dataitems uploaded by one owner address, newest first.
+#[instrument(skip_all, fields(owner = %owner))]
+pub async fn handle_owner_dataitems(
+    Path(owner): Path<String>,
+    State(indexer): State<Arc<dyn Indexer>>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Json<DataItemPage>, ApiError> {
+    let (page, _) = page_params(params)?;
+    let query = DataItemQuery { owners: vec![owner], ..Default::default() };
+    list_dataitems(indexer.as_ref(), query, page).await
+}
+
+/// Dataitems matching every `tag=name:value` param, repeated names match any of their values.
+/// `owner` narrows the search down to one or more owner addresses.
+#[instrument(skip_all)]
+pub async fn handle_search(
+    State(indexer): State<Arc<dyn Indexer>>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Json<DataItemPage>, ApiError> {
+    let (page, params) = page_params(params)?;
+
+    let mut query = DataItemQuery::default();
+    for (key, value) in params {
+        match key.as_str() {
+            "tag" => {
+                let (name, value) = value
+                    .split_once(':')
+                    .ok_or_else(|| bad_request(format!("tag {value} is not name:value")))?;
+                match query.tags.iter_mut().find(|filter| filter.name == name) {
+                    Some(filter) => filter.values.push(value.to_string()),
+                    None => query.tags.push(TagFilter {
+                        name: name.to_string(),
+                        values: vec![value.to_string()],
+                    }),
+                }
+            }
+            "owner" => query.owners.push(value),
+            _ => return Err(bad_request(format!("unknown search param {key}"))),
+        }
+    }
+    if query.tags.is_empty() && query.owners.is_empty() {
+        return Err(bad_request("search needs at least one tag or owner".to_string()));
+    }
+
+    list_dataitems(indexer.as_ref(), query, page).await
+}
@@ -0,0 +1,32 @@
+use sha2::{Digest, Sha384};
+
+pub type DeepHash = [u8; 48];
+
+fn sha384(chunks: &[&[u8]]) -> DeepHash {
+    let mut hasher = Sha384::new();
+    for chunk in chunks {
+        hasher.update(chunk);
+    }
+    hasher.finalize().into()
+}
+
+/// Arweave deepHash of a single blob.
+pub fn deep_hash_blob(data: &[u8]) -> DeepHash {
+    deep_hash_blob_digest(data.len() as u64, &sha384(&[data]))
+}
+
+/// Arweave deepHash of a blob whose SHA-384 digest was computed elsewhere,
+/// e.g. incrementally while the blob was streamed.
+pub fn deep_hash_blob_digest(len: u64, data_digest: &[u8]) -> DeepHash {
+    let tag = sha384(&[b"blob", len.to_string().as_bytes()]);
+    sha384(&[&tag, data_digest])
+}
+
+/// Arweave deepHash of a list, given the deepHash of each of its items.
+pub fn deep_hash_list(items: &[DeepHash]) -> DeepHash {
+    let mut acc = sha384(&[b"list", items.len().to_string().as_bytes()]);
+    for item in items {
+        acc = sha384(&[&acc, item]);
+    }
+    acc
+}
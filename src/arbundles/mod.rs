@@ -1,13 +1,25 @@
-use crate::utils::get_env_var;
+pub mod deep_hash;
+pub mod token;
+pub mod verify;
+
+use crate::{
+    config::Config,
+    metrics::METRICS,
+    utils::{LEGACY_RECEIPT_VERSION, RECEIPT_VERSION, get_env_var},
+};
+use anyhow::{Context, anyhow, bail};
 use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
-use bundles_rs::crypto::arweave::ArweaveSigner;
+use deep_hash::{DeepHash, deep_hash_blob, deep_hash_list};
 use rand::rngs::OsRng;
 use rsa::{
-    pss::BlindedSigningKey,
-    signature::{RandomizedSigner, SignatureEncoding},
+    BigUint, RsaPrivateKey, RsaPublicKey,
+    pss::{BlindedSigningKey, Signature, VerifyingKey},
+    signature::{RandomizedSigner, SignatureEncoding, Verifier},
+    traits::PublicKeyParts,
 };
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use verify::ARWEAVE_PUBLIC_EXPONENT;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -23,21 +35,36 @@ pub struct UnsignedReceipt {
     pub winc: String,
 }
 
+impl UnsignedReceipt {
+    /// A receipt for `id` in the current version, stamped now in ms.
+    pub fn new(config: &Config, id: String, owner: String) -> Self {
+        Self {
+            id,
+            deadline_height: config.receipt_height_deadline,
+            timestamp: chrono::Utc::now().timestamp_millis() as u64,
+            version: RECEIPT_VERSION.to_string(),
+            owner,
+            data_caches: config.data_caches.clone(),
+            fast_finality_indexes: config.fast_finality_indexes.clone(),
+            winc: "0".to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SignedReceipt {
     #[serde(flatten)]
     pub receipt: UnsignedReceipt,
-    // the bundler (uploader) public key
-    // used to verify the signature
+    // the bundler (uploader) public key used to verify the signature: the base64url RSA
+    // modulus only, the exponent is always 65537 as for every Arweave key
     pub public: String,
-    // signature consists of the hash of
-    // hash(id + version + timestamp + public key + deadlineHeight + owner)
+    // RSA-PSS signature over the receipt hash, see `prepare_hash` for what each version covers
     pub signature: String,
 }
 
-// Deep hash implementation
-fn deep_hash(chunks: &[&[u8]]) -> Vec<u8> {
+// length-prefixed SHA-256 the legacy receipt version was signed over
+fn legacy_hash(chunks: &[&[u8]]) -> Vec<u8> {
     let mut hasher = Sha256::new();
     for chunk in chunks {
         hasher.update((chunk.len() as u64).to_be_bytes());
@@ -46,46 +73,227 @@ fn deep_hash(chunks: &[&[u8]]) -> Vec<u8> {
     hasher.finalize().to_vec()
 }
 
-fn prepare_hash(receipt: &UnsignedReceipt) -> Vec<u8> {
-    deep_hash(&[
-        receipt.version.as_bytes(),
-        receipt.id.as_bytes(),
-        receipt.deadline_height.to_string().as_bytes(),
-        receipt.timestamp.to_string().as_bytes(),
-    ])
+fn deep_hash_strings(values: &[String]) -> DeepHash {
+    let items: Vec<DeepHash> = values.iter().map(|v| deep_hash_blob(v.as_bytes())).collect();
+    deep_hash_list(&items)
+}
+
+/// The bytes a receipt signature is made over, by receipt version:
+/// - `0.3.0`: Arweave deepHash of [version, id, deadlineHeight, timestamp, owner, public,
+///   [dataCaches...], [fastFinalityIndexes...], winc], numbers as decimal strings
+/// - `0.2.0`: length-prefixed SHA-256 of version, id, deadlineHeight and timestamp
+fn prepare_hash(receipt: &UnsignedReceipt, public: &str) -> anyhow::Result<Vec<u8>> {
+    match receipt.version.as_str() {
+        RECEIPT_VERSION => Ok(deep_hash_list(&[
+            deep_hash_blob(receipt.version.as_bytes()),
+            deep_hash_blob(receipt.id.as_bytes()),
+            deep_hash_blob(receipt.deadline_height.to_string().as_bytes()),
+            deep_hash_blob(receipt.timestamp.to_string().as_bytes()),
+            deep_hash_blob(receipt.owner.as_bytes()),
+            deep_hash_blob(public.as_bytes()),
+            deep_hash_strings(&receipt.data_caches),
+            deep_hash_strings(&receipt.fast_finality_indexes),
+            deep_hash_blob(receipt.winc.as_bytes()),
+        ])
+        .to_vec()),
+        LEGACY_RECEIPT_VERSION => Ok(legacy_hash(&[
+            receipt.version.as_bytes(),
+            receipt.id.as_bytes(),
+            receipt.deadline_height.to_string().as_bytes(),
+            receipt.timestamp.to_string().as_bytes(),
+        ])),
+        version => bail!("unknown receipt version {version}"),
+    }
+}
+/// The bundler key receipts are signed with, read once from the `UPLOADER_JWK` Arweave
+/// wallet at startup.
+pub struct ReceiptSigner {
+    private_key: RsaPrivateKey,
+    // base64url modulus, the `public` field of every receipt
+    public: String,
+}
+
+// the RSA components of an Arweave wallet JWK
+#[derive(Deserialize)]
+struct WalletJwk {
+    n: String,
+    e: String,
+    d: String,
+    p: String,
+    q: String,
+}
+
+impl ReceiptSigner {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::from_jwk_str(&get_env_var("UPLOADER_JWK")?).context("invalid UPLOADER_JWK")
+    }
+
+    pub fn from_jwk_str(jwk: &str) -> anyhow::Result<Self> {
+        let jwk: WalletJwk = serde_json::from_str(jwk).context("not an RSA private key JWK")?;
+        let component = |name: &str, value: &str| {
+            URL_SAFE_NO_PAD
+                .decode(value)
+                .map(|bytes| BigUint::from_bytes_be(&bytes))
+                .with_context(|| format!("{name} is not base64url"))
+        };
+        let private_key = RsaPrivateKey::from_components(
+            component("n", &jwk.n)?,
+            component("e", &jwk.e)?,
+            component("d", &jwk.d)?,
+            vec![component("p", &jwk.p)?, component("q", &jwk.q)?],
+        )?;
+        private_key.validate()?;
+        Self::new(private_key)
+    }
+
+    fn new(private_key: RsaPrivateKey) -> anyhow::Result<Self> {
+        // receipts only carry the modulus, verifiers assume the Arweave exponent
+        if private_key.e() != &BigUint::from(ARWEAVE_PUBLIC_EXPONENT) {
+            bail!("the receipt signing key must use the {ARWEAVE_PUBLIC_EXPONENT} public exponent");
+        }
+        let public = URL_SAFE_NO_PAD.encode(private_key.n().to_bytes_be());
+        Ok(Self { private_key, public })
+    }
+
+    /// The public key receipts are signed with, as found in their `public` field.
+    pub fn public(&self) -> &str {
+        &self.public
+    }
+
+    /// the function's logic follow the signReceipt.ts logic in https://github.com/ardriveapp/turbo-upload-service/blob/main/src/utils/signReceipt.ts
+    /// excluding the Bundlr/Irys backward-compatibility
+    pub fn sign(&self, receipt: UnsignedReceipt) -> anyhow::Result<SignedReceipt> {
+        let _signing_timer = METRICS.receipt_signing_seconds.start_timer();
+
+        // 1- prepare hash
+        let hash = prepare_hash(&receipt, &self.public)?;
+
+        // 2- sign with salt 0
+        let signing_key =
+            BlindedSigningKey::<Sha256>::new_with_salt_len(self.private_key.clone(), 0);
+        let mut rng = OsRng;
+        let signature_obj = signing_key.sign_with_rng(&mut rng, &hash);
+
+        // 3- convert to base64url
+        let signature = URL_SAFE_NO_PAD.encode(signature_obj.to_bytes());
+
+        Ok(SignedReceipt { receipt, public: self.public.clone(), signature })
+    }
+}
+
+/// Check a receipt signature against the key in `public`: rebuild the signed hash from the
+/// receipt fields and verify the RSA-PSS (salt 0) signature over it. This tells the receipt
+/// wasn't altered, not who signed it, compare `public` with [`ReceiptSigner::public`] for
+/// that.
+pub fn verify_receipt(receipt: &SignedReceipt) -> anyhow::Result<()> {
+    let n = URL_SAFE_NO_PAD.decode(&receipt.public).context("public is not base64url")?;
+    // Arweave keys always use the 65537 public exponent, checked when signing
+    let public_key =
+        RsaPublicKey::new(BigUint::from_bytes_be(&n), BigUint::from(ARWEAVE_PUBLIC_EXPONENT))
+            .context("public is not an RSA modulus")?;
+
+    let signature_bytes =
+        URL_SAFE_NO_PAD.decode(&receipt.signature).context("signature is not base64url")?;
+    let signature = Signature::try_from(signature_bytes.as_slice())
+        .map_err(|_| anyhow!("malformed receipt signature"))?;
+
+    let hash = prepare_hash(&receipt.receipt, &receipt.public)?;
+    VerifyingKey::<Sha256>::new_with_salt_len(public_key, 0)
+        .verify(&hash, &signature)
+        .map_err(|_| anyhow!("receipt signature does not match its fields"))
 }
-/// the function's logic follow the signReceipt.ts logic in https://github.com/ardriveapp/turbo-upload-service/blob/main/src/utils/signReceipt.ts
-/// excluding the Bundlr/Irys backward-compatibility
-pub fn sign_receipt(receipt: UnsignedReceipt) -> Result<SignedReceipt, Box<dyn std::error::Error>> {
-    let jwk_str = get_env_var("UPLOADER_JWK")?;
-    let jwk = ArweaveSigner::from_jwk_str(&jwk_str)?.to_jwk()?;
-
-    // private key components
-    let n = URL_SAFE_NO_PAD.decode(&jwk.n)?;
-    let e = URL_SAFE_NO_PAD.decode(&jwk.e)?;
-    let d = URL_SAFE_NO_PAD.decode(jwk.d.as_ref().ok_or("Missing private key")?)?;
-    let p = URL_SAFE_NO_PAD.decode(jwk.p.as_ref().ok_or("Missing p")?)?;
-    let q = URL_SAFE_NO_PAD.decode(jwk.q.as_ref().ok_or("Missing q")?)?;
-
-    // recreate RSA private key
-    let n_big = rsa::BigUint::from_bytes_be(&n);
-    let e_big = rsa::BigUint::from_bytes_be(&e);
-    let d_big = rsa::BigUint::from_bytes_be(&d);
-    let primes = vec![rsa::BigUint::from_bytes_be(&p), rsa::BigUint::from_bytes_be(&q)];
-
-    let private_key = rsa::RsaPrivateKey::from_components(n_big, e_big, d_big, primes)?;
-
-    // 1- prepare hash
-    let hash = prepare_hash(&receipt);
-
-    // 2- sign with salt 0
-    let signing_key = BlindedSigningKey::<Sha256>::new_with_salt_len(private_key, 0);
-    let mut rng = OsRng;
-    let signature_obj = signing_key.sign_with_rng(&mut rng, &hash);
-
-    // 3- convert to base64url
-    let signature = URL_SAFE_NO_PAD.encode(signature_obj.to_bytes());
-    let public = jwk.n;
-
-    Ok(SignedReceipt { receipt, public, signature })
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the arweave-rs test wallet, and a 0.2.0 receipt it signed
+    const WALLET: &str = include_str!("testdata/arweave_wallet.json");
+    const LEGACY_RECEIPT: &str = include_str!("testdata/receipt_0.2.0.json");
+
+    fn signer() -> ReceiptSigner {
+        ReceiptSigner::from_jwk_str(WALLET).unwrap()
+    }
+
+    fn receipt() -> UnsignedReceipt {
+        UnsignedReceipt {
+            id: "KRsvRmkwFpdCeTwmZu0Ujyz6wXAo4Ndv5Azn0oLupvU".to_string(),
+            deadline_height: 1_700_000,
+            timestamp: 1_718_000_000_000,
+            version: RECEIPT_VERSION.to_string(),
+            owner: "ggHWyKn0I_CTtsyyt2OR85sPYz9OvKLd9DYIvRQ2ET4".to_string(),
+            data_caches: vec!["arweave.net".to_string()],
+            fast_finality_indexes: vec!["arweave.net".to_string()],
+            winc: "0".to_string(),
+        }
+    }
+
+    #[test]
+    fn signed_receipts_verify() {
+        let signed = signer().sign(receipt()).unwrap();
+        verify_receipt(&signed).unwrap();
+
+        // a round trip through JSON, as clients hand receipts back
+        let json = serde_json::to_string(&signed).unwrap();
+        verify_receipt(&serde_json::from_str(&json).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn altered_receipts_do_not_verify() {
+        let signed = signer().sign(receipt()).unwrap();
+
+        let alterations: [fn(&mut SignedReceipt); 4] = [
+            |signed| signed.receipt.owner = "another-owner".to_string(),
+            |signed| signed.receipt.timestamp += 1,
+            |signed| signed.receipt.data_caches.clear(),
+            |signed| signed.receipt.winc = "1".to_string(),
+        ];
+        for alter in alterations {
+            let mut altered: SignedReceipt =
+                serde_json::from_str(&serde_json::to_string(&signed).unwrap()).unwrap();
+            alter(&mut altered);
+            assert!(verify_receipt(&altered).is_err());
+        }
+    }
+
+    #[test]
+    fn signers_need_an_arweave_private_key() {
+        let wallet: serde_json::Value = serde_json::from_str(WALLET).unwrap();
+        assert_eq!(signer().public(), wallet["n"]);
+
+        let mut public_only = wallet.clone();
+        public_only.as_object_mut().unwrap().remove("d");
+        let mut altered = wallet.clone();
+        altered["p"] = wallet["q"].clone();
+        for jwk in ["", "{}", &public_only.to_string(), &altered.to_string()] {
+            assert!(ReceiptSigner::from_jwk_str(jwk).is_err(), "{jwk:?} loaded");
+        }
+
+        // receipts carry the modulus alone, the exponent has to be the arweave one
+        let key = RsaPrivateKey::new_with_exp(&mut OsRng, 1024, &BigUint::from(3u32)).unwrap();
+        assert!(ReceiptSigner::new(key).is_err());
+    }
+
+    #[test]
+    fn legacy_receipts_verify() {
+        let legacy: SignedReceipt = serde_json::from_str(LEGACY_RECEIPT).unwrap();
+        assert_eq!(legacy.receipt.version, LEGACY_RECEIPT_VERSION);
+        verify_receipt(&legacy).unwrap();
+
+        // 0.2.0 signatures only cover version, id, deadlineHeight and timestamp
+        let mut owner_changed: SignedReceipt = serde_json::from_str(LEGACY_RECEIPT).unwrap();
+        owner_changed.receipt.owner = "another-owner".to_string();
+        verify_receipt(&owner_changed).unwrap();
+
+        let mut id_changed: SignedReceipt = serde_json::from_str(LEGACY_RECEIPT).unwrap();
+        id_changed.receipt.id = receipt().owner;
+        assert!(verify_receipt(&id_changed).is_err());
+    }
+
+    #[test]
+    fn unknown_receipt_versions_do_not_verify() {
+        let mut legacy: SignedReceipt = serde_json::from_str(LEGACY_RECEIPT).unwrap();
+        legacy.receipt.version = "0.1.0".to_string();
+        assert!(verify_receipt(&legacy).is_err());
+    }
 }
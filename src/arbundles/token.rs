@@ -0,0 +1,136 @@
+use crate::arbundles::verify::{
+    SIG_TYPE_ARWEAVE, SIG_TYPE_ED25519, SIG_TYPE_ETHEREUM, SIG_TYPE_SOLANA,
+};
+use serde::{Deserialize, Serialize, de::IntoDeserializer};
+use std::{fmt, str::FromStr};
+
+/// The `{token}` path segment of the upload routes, as sent by the Turbo SDK. It names the
+/// wallet type the DataItem was signed with, so it has to agree with its signature type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Token {
+    Arweave,
+    Ario,
+    BaseArio,
+    Ethereum,
+    BaseEth,
+    Matic,
+    Pol,
+    Usdc,
+    BaseUsdc,
+    PolygonUsdc,
+    Kyve,
+    Solana,
+    Ed25519,
+}
+
+impl Token {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Arweave => "arweave",
+            Self::Ario => "ario",
+            Self::BaseArio => "base-ario",
+            Self::Ethereum => "ethereum",
+            Self::BaseEth => "base-eth",
+            Self::Matic => "matic",
+            Self::Pol => "pol",
+            Self::Usdc => "usdc",
+            Self::BaseUsdc => "base-usdc",
+            Self::PolygonUsdc => "polygon-usdc",
+            Self::Kyve => "kyve",
+            Self::Solana => "solana",
+            Self::Ed25519 => "ed25519",
+        }
+    }
+
+    /// Whether a DataItem with this ANS-104 signature type can be uploaded under the token.
+    pub fn accepts_signature_type(&self, signature_type: u16) -> bool {
+        match self {
+            Self::Arweave | Self::Ario => signature_type == SIG_TYPE_ARWEAVE,
+            // EVM and KYVE wallets all sign with secp256k1
+            Self::BaseArio
+            | Self::Ethereum
+            | Self::BaseEth
+            | Self::Matic
+            | Self::Pol
+            | Self::Usdc
+            | Self::BaseUsdc
+            | Self::PolygonUsdc
+            | Self::Kyve => signature_type == SIG_TYPE_ETHEREUM,
+            // Solana signers produce plain ed25519 DataItems as well
+            Self::Solana | Self::Ed25519 => {
+                signature_type == SIG_TYPE_ED25519 || signature_type == SIG_TYPE_SOLANA
+            }
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Token {
+    type Err = serde::de::value::Error;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        Self::deserialize(token.into_deserializer())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARWEAVE: &[u16] = &[SIG_TYPE_ARWEAVE];
+    const ETHEREUM: &[u16] = &[SIG_TYPE_ETHEREUM];
+    const ED25519: &[u16] = &[SIG_TYPE_ED25519, SIG_TYPE_SOLANA];
+
+    // path name and accepted signature types of every token
+    const TOKENS: [(Token, &str, &[u16]); 13] = [
+        (Token::Arweave, "arweave", ARWEAVE),
+        (Token::Ario, "ario", ARWEAVE),
+        (Token::BaseArio, "base-ario", ETHEREUM),
+        (Token::Ethereum, "ethereum", ETHEREUM),
+        (Token::BaseEth, "base-eth", ETHEREUM),
+        (Token::Matic, "matic", ETHEREUM),
+        (Token::Pol, "pol", ETHEREUM),
+        (Token::Usdc, "usdc", ETHEREUM),
+        (Token::BaseUsdc, "base-usdc", ETHEREUM),
+        (Token::PolygonUsdc, "polygon-usdc", ETHEREUM),
+        (Token::Kyve, "kyve", ETHEREUM),
+        (Token::Solana, "solana", ED25519),
+        (Token::Ed25519, "ed25519", ED25519),
+    ];
+
+    #[test]
+    fn path_names_are_kebab_case() {
+        for (token, name, _) in TOKENS {
+            assert_eq!(token.as_str(), name);
+            assert_eq!(token.to_string(), name);
+            assert_eq!(serde_json::from_value::<Token>(name.into()).unwrap(), token);
+            assert_eq!(serde_json::to_string(&token).unwrap(), format!("\"{name}\""));
+        }
+    }
+
+    #[test]
+    fn unknown_path_names_are_refused() {
+        for name in ["", "Arweave", "base_eth", "BASE-ETH", "bitcoin", "arweave "] {
+            assert!(serde_json::from_value::<Token>(name.into()).is_err(), "{name:?} parsed");
+        }
+    }
+
+    #[test]
+    fn tokens_accept_their_signature_types_only() {
+        for (token, _, accepted) in TOKENS {
+            for signature_type in 0..=5 {
+                assert_eq!(
+                    token.accepts_signature_type(signature_type),
+                    accepted.contains(&signature_type),
+                    "{token} with signature type {signature_type}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use crate::{
+    arbundles::deep_hash::{DeepHash, deep_hash_blob, deep_hash_list},
+    utils::DataItemHeader,
+};
+use anyhow::{Error, anyhow};
+use k256::ecdsa::{
+    Signature as EcdsaSignature, VerifyingKey as EcdsaVerifyingKey,
+    signature::hazmat::PrehashVerifier,
+};
+use rsa::{BigUint, RsaPublicKey, pss, signature::Verifier, traits::PublicKeyParts};
+use sha2::Sha256;
+use sha3::{Digest, Keccak256};
+use std::fmt;
+
+// ANS-104 signature types, see
+// https://github.com/ArweaveTeam/arweave-standards/blob/master/ans/ANS-104.md#21-verifying-a-dataitem
+pub(crate) const SIG_TYPE_ARWEAVE: u16 = 1;
+pub(crate) const SIG_TYPE_ED25519: u16 = 2;
+pub(crate) const SIG_TYPE_ETHEREUM: u16 = 3;
+pub(crate) const SIG_TYPE_SOLANA: u16 = 4;
+
+pub(crate) const ARWEAVE_PUBLIC_EXPONENT: u32 = 65537;
+
+/// Returned (wrapped in an [`anyhow::Error`]) when a DataItem fails signature
+/// verification, so callers can tell a bad upload apart from an internal failure.
+#[derive(Debug)]
+pub struct InvalidDataItem(pub String);
+
+impl fmt::Display for InvalidDataItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid dataitem: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidDataItem {}
+
+/// Short name of an ANS-104 signature type, used as a metrics label.
+pub fn signature_type_name(signature_type: u16) -> &'static str {
+    match signature_type {
+        SIG_TYPE_ARWEAVE => "arweave",
+        SIG_TYPE_ED25519 => "ed25519",
+        SIG_TYPE_ETHEREUM => "ethereum",
+        SIG_TYPE_SOLANA => "solana",
+        _ => "unknown",
+    }
+}
+
+/// The message an ANS-104 DataItem signature is computed over:
+/// deepHash(["dataitem", "1", signatureType, owner, target, anchor, tags, data]).
+/// `data_hash` is the deepHash of the data payload.
+pub fn dataitem_signature_message(header: &DataItemHeader, data_hash: DeepHash) -> DeepHash {
+    let target = header.target.map(|t| t.to_vec()).unwrap_or_default();
+    let anchor = header.anchor.clone().unwrap_or_default();
+
+    deep_hash_list(&[
+        deep_hash_blob(b"dataitem"),
+        deep_hash_blob(b"1"),
+        deep_hash_blob(header.signature_type.to_string().as_bytes()),
+        deep_hash_blob(&header.owner),
+        deep_hash_blob(&target),
+        deep_hash_blob(&anchor),
+        deep_hash_blob(&header.raw_tags),
+        data_hash,
+    ])
+}
+
+/// Verify the DataItem signature against an already computed signature message.
+pub fn verify_dataitem_signature(header: &DataItemHeader, message: DeepHash) -> Result<(), Error> {
+    let res = match header.signature_type {
+        SIG_TYPE_ARWEAVE => verify_arweave(&header.owner, &header.signature, &message),
+        SIG_TYPE_ED25519 | SIG_TYPE_SOLANA => {
+            verify_ed25519(&header.owner, &header.signature, &message)
+        }
+        SIG_TYPE_ETHEREUM => verify_ethereum(&header.owner, &header.signature, &message),
+        other => Err(anyhow!("unsupported signature type {other}")),
+    };
+
+    res.map_err(|e| Error::new(InvalidDataItem(e.to_string())))
+}
+
+// RSA-PSS (SHA-256) with the 4096 bits owner modulus. Browsers sign with a 32 bytes salt,
+// node (arbundles, arweave-js) with the longest salt the modulus allows, both are accepted
+fn verify_arweave(owner: &[u8], signature: &[u8], message: &[u8]) -> Result<(), Error> {
+    let public_key =
+        RsaPublicKey::new(BigUint::from_bytes_be(owner), BigUint::from(ARWEAVE_PUBLIC_EXPONENT))?;
+    let signature = pss::Signature::try_from(signature)?;
+
+    let encoded_len = (public_key.n().bits() - 1).div_ceil(8);
+    let max_salt_len = encoded_len.saturating_sub(<Sha256 as Digest>::output_size() + 2);
+    let verified = [<Sha256 as Digest>::output_size(), max_salt_len].into_iter().any(|salt_len| {
+        pss::VerifyingKey::<Sha256>::new_with_salt_len(public_key.clone(), salt_len)
+            .verify(message, &signature)
+            .is_ok()
+    });
+    if !verified {
+        return Err(anyhow!("invalid arweave signature"));
+    }
+    Ok(())
+}
+
+fn verify_ed25519(owner: &[u8], signature: &[u8], message: &[u8]) -> Result<(), Error> {
+    let owner: &[u8; 32] = owner.try_into().map_err(|_| anyhow!("invalid ed25519 owner length"))?;
+    let signature: &[u8; 64] =
+        signature.try_into().map_err(|_| anyhow!("invalid ed25519 signature length"))?;
+
+    let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(owner)?;
+    let signature = ed25519_dalek::Signature::from_bytes(signature);
+    verifying_key
+        .verify_strict(message, &signature)
+        .map_err(|_| anyhow!("invalid ed25519 signature"))
+}
+
+// secp256k1 over the EIP-191 personal message of the deep hash, as done by
+// the ethers signer in arbundles
+fn verify_ethereum(owner: &[u8], signature: &[u8], message: &[u8]) -> Result<(), Error> {
+    if signature.len() != 65 {
+        return Err(anyhow!("invalid ethereum signature length"));
+    }
+
+    let mut hasher = Keccak256::new();
+    hasher.update(format!("\x19Ethereum Signed Message:\n{}", message.len()).as_bytes());
+    hasher.update(message);
+    let prehash = hasher.finalize();
+
+    let verifying_key = EcdsaVerifyingKey::from_sec1_bytes(owner)?;
+    // drop the recovery id (v)
+    let signature = EcdsaSignature::from_slice(&signature[..64])?;
+    let signature = signature.normalize_s().unwrap_or(signature);
+    verifying_key
+        .verify_prehash(&prehash, &signature)
+        .map_err(|_| anyhow!("invalid ethereum signature"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::parse_dataitem_header;
+    use std::io::Cursor;
+
+    // single DataItems signed by arbundles, taken from the bundlr-sdk test bundles
+    const ARWEAVE_DATAITEM: &[u8] = include_bytes!("testdata/arweave.ans104");
+    // the arweave one signed again with a 32 bytes salt, as browser wallets do
+    const ARWEAVE_SALT32_DATAITEM: &[u8] = include_bytes!("testdata/arweave_salt32.ans104");
+    const ED25519_DATAITEM: &[u8] = include_bytes!("testdata/ed25519.ans104");
+    const ETHEREUM_DATAITEM: &[u8] = include_bytes!("testdata/ethereum.ans104");
+
+    fn signed(dataitem: &[u8]) -> (DataItemHeader, DeepHash) {
+        let header = parse_dataitem_header(&mut Cursor::new(dataitem)).unwrap();
+        let data_hash = deep_hash_blob(&dataitem[header.data_offset..]);
+        let message = dataitem_signature_message(&header, data_hash);
+        (header, message)
+    }
+
+    fn tampered(bytes: &[u8]) -> Vec<u8> {
+        let mut bytes = bytes.to_vec();
+        bytes[10] ^= 0x01;
+        bytes
+    }
+
+    #[test]
+    fn arweave_vector() {
+        let (header, message) = signed(ARWEAVE_DATAITEM);
+        assert_eq!(header.signature_type, SIG_TYPE_ARWEAVE);
+        verify_arweave(&header.owner, &header.signature, &message).unwrap();
+        assert!(verify_arweave(&header.owner, &tampered(&header.signature), &message).is_err());
+        assert!(verify_arweave(&header.owner, &header.signature, &tampered(&message)).is_err());
+    }
+
+    #[test]
+    fn arweave_vector_with_a_short_salt() {
+        let (header, message) = signed(ARWEAVE_SALT32_DATAITEM);
+        verify_arweave(&header.owner, &header.signature, &message).unwrap();
+        assert!(verify_arweave(&header.owner, &tampered(&header.signature), &message).is_err());
+    }
+
+    #[test]
+    fn ed25519_vector() {
+        let (header, message) = signed(ED25519_DATAITEM);
+        assert_eq!(header.signature_type, SIG_TYPE_ED25519);
+        verify_ed25519(&header.owner, &header.signature, &message).unwrap();
+        assert!(verify_ed25519(&header.owner, &tampered(&header.signature), &message).is_err());
+        assert!(verify_ed25519(&header.owner, &header.signature, &tampered(&message)).is_err());
+    }
+
+    #[test]
+    fn ethereum_vector() {
+        // EIP-191 personal message over the 48 bytes deep hash
+        let (header, message) = signed(ETHEREUM_DATAITEM);
+        assert_eq!(header.signature_type, SIG_TYPE_ETHEREUM);
+        assert_eq!(message.len(), 48);
+        verify_ethereum(&header.owner, &header.signature, &message).unwrap();
+        assert!(verify_ethereum(&header.owner, &tampered(&header.signature), &message).is_err());
+        assert!(verify_ethereum(&header.owner, &header.signature, &tampered(&message)).is_err());
+    }
+
+    #[test]
+    fn tampered_dataitems_are_invalid() {
+        for dataitem in
+            [ARWEAVE_DATAITEM, ARWEAVE_SALT32_DATAITEM, ED25519_DATAITEM, ETHEREUM_DATAITEM]
+        {
+            let (header, message) = signed(dataitem);
+            verify_dataitem_signature(&header, message).unwrap();
+
+            // a flipped data byte changes the signed message
+            let mut data = dataitem.to_vec();
+            *data.last_mut().unwrap() ^= 0x01;
+            let (header, message) = signed(&data);
+            let e = verify_dataitem_signature(&header, message).unwrap_err();
+            assert!(e.downcast_ref::<InvalidDataItem>().is_some());
+        }
+    }
+}
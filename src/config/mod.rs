@@ -0,0 +1,319 @@
+use anyhow::{Context, Error, anyhow, ensure};
+use serde::Deserialize;
+use std::{env, path::Path, str::FromStr};
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+const S3_MIN_PART_SIZE: usize = 1024 * 1024 * 5; // 5MiB - AWS minimum
+const S3_MAX_PART_SIZE: usize = 1024 * 1024 * 1024 * 5; // 5GiB - AWS maximum
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    S3,
+    Fs,
+}
+
+impl FromStr for StorageKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "s3" => Ok(Self::S3),
+            "fs" => Ok(Self::Fs),
+            other => Err(anyhow!("unknown storage backend {other}, expected s3 or fs")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexerKind {
+    Clickhouse,
+    Sqlite,
+    Disabled,
+}
+
+impl FromStr for IndexerKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "clickhouse" => Ok(Self::Clickhouse),
+            "sqlite" => Ok(Self::Sqlite),
+            "disabled" => Ok(Self::Disabled),
+            other => {
+                Err(anyhow!("unknown indexer {other}, expected clickhouse, sqlite or disabled"))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            other => Err(anyhow!("unknown log format {other}, expected pretty or json")),
+        }
+    }
+}
+
+/// Runtime configuration, loaded once at startup from a TOML file (`CONFIG_PATH`, defaults
+/// to `./config.toml` when present) and then overridden field by field from env vars of the
+/// same name in upper case, e.g. `DATA_CACHES=https://a,https://b`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server_port: u16,
+    pub data_caches: Vec<String>,
+    pub fast_finality_indexes: Vec<String>,
+    // load-s3-agent address
+    pub uploader_ar_address: String,
+    // largest item an owner without its own or a default quota may store, only advertised in
+    // /info unless enforce_free_limit is set
+    pub free_upload_limit_bytes: u32,
+    pub enforce_free_limit: bool,
+    // per-owner byte quotas for owners without their own, unlimited when unset
+    pub default_daily_quota_bytes: Option<u64>,
+    pub default_total_quota_bytes: Option<u64>,
+    // ported from https://github.com/ardriveapp/turbo-upload-service/blob/main/src/constants.ts#L298
+    pub chunk_min_size: usize,
+    pub chunk_max_size: usize,
+    // a 5 years projection based on 2min blocktime,
+    // counting from block #1764397
+    pub receipt_height_deadline: u64,
+    pub object_size_limit: usize,
+    // where dataitems are stored, S3 settings come from the AWS_* and S3_* env vars
+    pub storage_backend: StorageKind,
+    // root directory of the fs storage backend
+    pub storage_fs_root: String,
+    // where dataitem tags are indexed, ClickHouse settings come from the CLICKHOUSE_* env vars
+    pub indexer: IndexerKind,
+    // tag rows are buffered across dataitems and flushed to ClickHouse
+    // every clickhouse_batch_max_rows rows or clickhouse_batch_period_ms, an upload waits
+    // for the commit holding its rows
+    pub clickhouse_batch_max_rows: u64,
+    pub clickhouse_batch_period_ms: u64,
+    // log levels are set with RUST_LOG
+    pub log_format: LogFormat,
+    // bearer token for the /admin routes, which are not served when unset
+    pub admin_token: Option<String>,
+    // how often the owner access policy is reloaded from SQLite
+    pub access_refresh_secs: u64,
+    // multipart sessions without a chunk for this long are aborted,
+    // checked every multipart_sweep_interval_secs
+    pub multipart_upload_ttl_secs: u64,
+    pub multipart_sweep_interval_secs: u64,
+    // an expired session whose abort failed this many sweeps is marked failed as is
+    pub multipart_sweep_max_attempts: u32,
+    // multipart sessions assembled and validated at the same time after finalize
+    pub finalize_workers: usize,
+    // a finalize failing on a backend error is retried after finalize_retry_backoff_secs,
+    // doubled on every attempt, and the session failed after finalize_max_attempts runs unless
+    // its dataitem is already copied to its final key
+    pub finalize_max_attempts: u32,
+    pub finalize_retry_backoff_secs: u64,
+    // sessions still finalizing this long after the request are failed, or queued again once
+    // copied, checked every multipart_sweep_interval_secs
+    pub finalize_timeout_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server_port: 3000,
+            data_caches: vec!["https://gateway.s3-node-1.load.network".to_string()],
+            fast_finality_indexes: vec!["https://gateway.s3-node-1.load.network".to_string()],
+            uploader_ar_address: "2BBwe2pSXn_Tp-q_mHry0Obp88dc7L-eDIWx0_BUfD0".to_string(),
+            free_upload_limit_bytes: 1048576,
+            enforce_free_limit: false,
+            default_daily_quota_bytes: None,
+            default_total_quota_bytes: None,
+            chunk_min_size: S3_MIN_PART_SIZE,
+            chunk_max_size: 1024 * 1024 * 500, // 500MiB // NOTE: S3 cluster supports upto 5GiB
+            receipt_height_deadline: 3_079_297,
+            object_size_limit: 4 * 1_073_741_824, // 4 GB
+            storage_backend: StorageKind::S3,
+            storage_fs_root: "./data".to_string(),
+            indexer: IndexerKind::Clickhouse,
+            clickhouse_batch_max_rows: 10_000,
+            clickhouse_batch_period_ms: 1_000,
+            log_format: LogFormat::Pretty,
+            admin_token: None,
+            access_refresh_secs: 30,
+            multipart_upload_ttl_secs: 24 * 60 * 60,
+            multipart_sweep_interval_secs: 60 * 60,
+            multipart_sweep_max_attempts: 5,
+            finalize_workers: 4,
+            finalize_max_attempts: 5,
+            finalize_retry_backoff_secs: 5,
+            finalize_timeout_secs: 60 * 60,
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self, Error> {
+        let mut config = match env::var("CONFIG_PATH") {
+            Ok(path) => Self::from_file(&path)?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(DEFAULT_CONFIG_PATH)?
+            }
+            Err(_) => Self::default(),
+        };
+
+        config.apply_env_overrides()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Self, Error> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {path}"))?;
+        toml::from_str(&raw).with_context(|| format!("failed to parse config file {path}"))
+    }
+
+    fn apply_env_overrides(&mut self) -> Result<(), Error> {
+        override_parsed("SERVER_PORT", &mut self.server_port)?;
+        override_list("DATA_CACHES", &mut self.data_caches);
+        override_list("FAST_FINALITY_INDEXES", &mut self.fast_finality_indexes);
+        if let Ok(address) = env::var("UPLOADER_AR_ADDRESS") {
+            self.uploader_ar_address = address;
+        }
+        override_parsed("FREE_UPLOAD_LIMIT_BYTES", &mut self.free_upload_limit_bytes)?;
+        override_parsed("ENFORCE_FREE_LIMIT", &mut self.enforce_free_limit)?;
+        override_optional("DEFAULT_DAILY_QUOTA_BYTES", &mut self.default_daily_quota_bytes)?;
+        override_optional("DEFAULT_TOTAL_QUOTA_BYTES", &mut self.default_total_quota_bytes)?;
+        override_parsed("CHUNK_MIN_SIZE", &mut self.chunk_min_size)?;
+        override_parsed("CHUNK_MAX_SIZE", &mut self.chunk_max_size)?;
+        override_parsed("RECEIPT_HEIGHT_DEADLINE", &mut self.receipt_height_deadline)?;
+        override_parsed("OBJECT_SIZE_LIMIT", &mut self.object_size_limit)?;
+        override_parsed("STORAGE_BACKEND", &mut self.storage_backend)?;
+        if let Ok(root) = env::var("STORAGE_FS_ROOT") {
+            self.storage_fs_root = root;
+        }
+        override_parsed("INDEXER", &mut self.indexer)?;
+        override_parsed("CLICKHOUSE_BATCH_MAX_ROWS", &mut self.clickhouse_batch_max_rows)?;
+        override_parsed("CLICKHOUSE_BATCH_PERIOD_MS", &mut self.clickhouse_batch_period_ms)?;
+        override_parsed("LOG_FORMAT", &mut self.log_format)?;
+        if let Ok(token) = env::var("ADMIN_TOKEN") {
+            self.admin_token = Some(token).filter(|token| !token.is_empty());
+        }
+        override_parsed("ACCESS_REFRESH_SECS", &mut self.access_refresh_secs)?;
+        override_parsed("MULTIPART_UPLOAD_TTL_SECS", &mut self.multipart_upload_ttl_secs)?;
+        override_parsed("MULTIPART_SWEEP_INTERVAL_SECS", &mut self.multipart_sweep_interval_secs)?;
+        override_parsed("MULTIPART_SWEEP_MAX_ATTEMPTS", &mut self.multipart_sweep_max_attempts)?;
+        override_parsed("FINALIZE_WORKERS", &mut self.finalize_workers)?;
+        override_parsed("FINALIZE_MAX_ATTEMPTS", &mut self.finalize_max_attempts)?;
+        override_parsed("FINALIZE_RETRY_BACKOFF_SECS", &mut self.finalize_retry_backoff_secs)?;
+        override_parsed("FINALIZE_TIMEOUT_SECS", &mut self.finalize_timeout_secs)?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        ensure!(self.server_port != 0, "server_port must not be 0");
+        ensure!(!self.data_caches.is_empty(), "at least one data cache is required");
+        ensure!(
+            !self.fast_finality_indexes.is_empty(),
+            "at least one fast finality index is required"
+        );
+        for url in self.data_caches.iter().chain(&self.fast_finality_indexes) {
+            ensure!(
+                url.starts_with("http://") || url.starts_with("https://"),
+                "invalid gateway url {url}"
+            );
+        }
+        ensure!(!self.uploader_ar_address.is_empty(), "uploader_ar_address is required");
+        ensure!(
+            self.chunk_min_size >= S3_MIN_PART_SIZE,
+            "chunk_min_size must be at least {S3_MIN_PART_SIZE} bytes"
+        );
+        ensure!(
+            self.chunk_max_size <= S3_MAX_PART_SIZE,
+            "chunk_max_size must be at most {S3_MAX_PART_SIZE} bytes"
+        );
+        ensure!(
+            self.chunk_min_size <= self.chunk_max_size,
+            "chunk_min_size must not exceed chunk_max_size"
+        );
+        ensure!(self.object_size_limit > 0, "object_size_limit must be greater than 0");
+        if self.storage_backend == StorageKind::Fs {
+            ensure!(!self.storage_fs_root.is_empty(), "storage_fs_root is required");
+        }
+        if self.indexer == IndexerKind::Clickhouse {
+            ensure!(
+                self.clickhouse_batch_max_rows > 0,
+                "clickhouse_batch_max_rows must be greater than 0"
+            );
+            ensure!(
+                self.clickhouse_batch_period_ms > 0,
+                "clickhouse_batch_period_ms must be greater than 0"
+            );
+        }
+        ensure!(
+            self.admin_token.as_deref().is_none_or(|token| !token.trim().is_empty()),
+            "admin_token must not be empty, leave it unset to turn the admin routes off"
+        );
+        ensure!(self.access_refresh_secs > 0, "access_refresh_secs must be greater than 0");
+        ensure!(
+            self.multipart_upload_ttl_secs > 0,
+            "multipart_upload_ttl_secs must be greater than 0"
+        );
+        ensure!(
+            self.multipart_sweep_interval_secs > 0,
+            "multipart_sweep_interval_secs must be greater than 0"
+        );
+        ensure!(
+            self.multipart_sweep_max_attempts > 0,
+            "multipart_sweep_max_attempts must be greater than 0"
+        );
+        ensure!(self.finalize_workers > 0, "finalize_workers must be greater than 0");
+        ensure!(self.finalize_max_attempts > 0, "finalize_max_attempts must be greater than 0");
+        ensure!(self.finalize_timeout_secs > 0, "finalize_timeout_secs must be greater than 0");
+        Ok(())
+    }
+}
+
+fn override_parsed<T: FromStr>(key: &str, value: &mut T) -> Result<(), Error>
+where
+    T::Err: std::fmt::Display,
+{
+    if let Ok(raw) = env::var(key) {
+        *value = raw.trim().parse().map_err(|e| anyhow!("invalid {key}={raw}: {e}"))?;
+    }
+    Ok(())
+}
+
+// an empty value unsets the field
+fn override_optional<T: FromStr>(key: &str, value: &mut Option<T>) -> Result<(), Error>
+where
+    T::Err: std::fmt::Display,
+{
+    if let Ok(raw) = env::var(key) {
+        *value = match raw.trim() {
+            "" => None,
+            trimmed => Some(trimmed.parse().map_err(|e| anyhow!("invalid {key}={raw}: {e}"))?),
+        };
+    }
+    Ok(())
+}
+
+fn override_list(key: &str, value: &mut Vec<String>) {
+    if let Ok(raw) = env::var(key) {
+        *value = raw
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect();
+    }
+}
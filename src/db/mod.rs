@@ -1,5 +1,8 @@
-use crate::utils::get_env_var;
-use anyhow::Error;
+use crate::{
+    metrics::METRICS,
+    utils::{get_env_var, legacy_arweave_owner_address},
+};
+use anyhow::{Context, Error};
 use serde::{Deserialize, Serialize};
 use sqlx::{Row, SqlitePool};
 
@@ -26,7 +29,13 @@ pub(crate) async fn init_db() -> Result<SqlitePool, Error> {
     }
 
     let pool = SqlitePool::connect(&format!("sqlite:{db_path}")).await?;
+    create_tables(&pool).await?;
 
+    Ok(pool)
+}
+
+/// Create the upload tables, or bring the ones an older version created up to date.
+pub(crate) async fn create_tables(pool: &SqlitePool) -> Result<(), Error> {
     sqlx::query(
         r#"
         CREATE TABLE IF NOT EXISTS uploads (
@@ -35,13 +44,43 @@ pub(crate) async fn init_db() -> Result<SqlitePool, Error> {
             s3_upload_id TEXT NOT NULL,
             chunk_size INTEGER,
             created_at INTEGER NOT NULL,
-            failed_reason TEXT
+            failed_reason TEXT,
+            updated_at INTEGER,
+            status TEXT NOT NULL DEFAULT 'ASSEMBLING',
+            token TEXT,
+            finalize_requested_at INTEGER,
+            finalize_step TEXT,
+            owner_address TEXT,
+            abort_attempts INTEGER NOT NULL DEFAULT 0
         )
     "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
+    // tables created before sessions tracked their last chunk
+    add_column_if_missing(pool, "uploads", "updated_at", "INTEGER").await?;
+
+    // tables created before finalize ran in the background
+    if add_column_if_missing(pool, "uploads", "status", "TEXT NOT NULL DEFAULT 'ASSEMBLING'")
+        .await?
+    {
+        sqlx::query("UPDATE uploads SET status = 'FAILED' WHERE failed_reason IS NOT NULL")
+            .execute(pool)
+            .await?;
+    }
+    add_column_if_missing(pool, "uploads", "token", "TEXT").await?;
+    add_column_if_missing(pool, "uploads", "finalize_requested_at", "INTEGER").await?;
+
+    // tables created before finalize steps were recorded
+    add_column_if_missing(pool, "uploads", "finalize_step", "TEXT").await?;
+
+    // tables created before chunks were checked against the owner's quota
+    add_column_if_missing(pool, "uploads", "owner_address", "TEXT").await?;
+
+    // tables created before failed sweeper aborts were counted
+    add_column_if_missing(pool, "uploads", "abort_attempts", "INTEGER NOT NULL DEFAULT 0").await?;
+
     sqlx::query(
         r#"
         CREATE TABLE IF NOT EXISTS chunks (
@@ -53,7 +92,7 @@ pub(crate) async fn init_db() -> Result<SqlitePool, Error> {
         )
     "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
     // New table to store completed upload information
@@ -67,10 +106,140 @@ pub(crate) async fn init_db() -> Result<SqlitePool, Error> {
         )
     "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
-    Ok(pool)
+    // one signed receipt per dataitem, kept as returned to the client
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS receipts (
+            dataitem_id TEXT PRIMARY KEY,
+            receipt TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        )
+    "#,
+    )
+    .execute(pool)
+    .await?;
+
+    migrate_arweave_owner_addresses(pool, "uploads", "owner_address").await?;
+    migrate_arweave_owner_addresses(pool, "completed_uploads", "owner_address").await?;
+
+    Ok(())
+}
+
+/// An in-memory database with the upload tables, for tests.
+#[cfg(test)]
+pub(crate) async fn test_pool() -> SqlitePool {
+    // a single connection, every in-memory connection is a database of its own
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    create_tables(&pool).await.unwrap();
+    pool
+}
+
+/// Add `column` with the `ddl` type and constraints to a `table` created before it existed.
+/// Returns whether it had to be added.
+pub(crate) async fn add_column_if_missing(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    ddl: &str,
+) -> Result<bool, Error> {
+    let exists: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?")
+            .bind(table)
+            .bind(column)
+            .fetch_one(pool)
+            .await?;
+    if exists > 0 {
+        return Ok(false);
+    }
+
+    sqlx::query(&format!("ALTER TABLE {table} ADD COLUMN {column} {ddl}"))
+        .execute(pool)
+        .await
+        .with_context(|| format!("failed to add {table}.{column} column"))?;
+    Ok(true)
+}
+
+/// Rewrite the Arweave owners `table`.`column` still holds as a base64url modulus, the form
+/// they were stored in before owner addresses were wallet addresses. A row whose address is
+/// taken already, in a table keyed by owner, gives way to the one stored under the address.
+pub(crate) async fn migrate_arweave_owner_addresses(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+) -> Result<(), Error> {
+    // a base64url 512-byte modulus is 683 characters long, no address is
+    let legacy: Vec<String> = sqlx::query_scalar(&format!(
+        "SELECT DISTINCT {column} FROM {table} WHERE length({column}) = 683"
+    ))
+    .fetch_all(pool)
+    .await?;
+
+    for stored in legacy {
+        let Some(address) = legacy_arweave_owner_address(&stored) else {
+            continue;
+        };
+        sqlx::query(&format!("UPDATE OR IGNORE {table} SET {column} = ? WHERE {column} = ?"))
+            .bind(&address)
+            .bind(&stored)
+            .execute(pool)
+            .await?;
+        sqlx::query(&format!("DELETE FROM {table} WHERE {column} = ?"))
+            .bind(&stored)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+// States of a multipart session as reported by its status route, a finalized session has
+// its row moved to completed_uploads
+pub const UPLOAD_ASSEMBLING: &str = "ASSEMBLING";
+pub const UPLOAD_VALIDATING: &str = "VALIDATING";
+pub const UPLOAD_FAILED: &str = "FAILED";
+
+/// The finalize steps of a multipart session, in order. The last one completed is kept on
+/// the upload row so an interrupted finalize resumes right after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum FinalizeStep {
+    // the parts are joined into the upload key object
+    Assembled,
+    // the dataitem passed its checks and its completed_uploads row is stored
+    Validated,
+    // the dataitem is at its final key
+    Copied,
+    Indexed,
+    // its bytes count against the owner quota
+    Recorded,
+}
+
+impl FinalizeStep {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Assembled => "assembled",
+            Self::Validated => "validated",
+            Self::Copied => "copied",
+            Self::Indexed => "indexed",
+            Self::Recorded => "recorded",
+        }
+    }
+
+    fn parse(step: &str) -> Result<Self, Error> {
+        match step {
+            "assembled" => Ok(Self::Assembled),
+            "validated" => Ok(Self::Validated),
+            "copied" => Ok(Self::Copied),
+            "indexed" => Ok(Self::Indexed),
+            "recorded" => Ok(Self::Recorded),
+            step => Err(anyhow::anyhow!("unknown finalize step {step}")),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -80,6 +249,30 @@ pub struct InFlightUpload {
     pub s3_upload_id: String,
     pub chunk_size: Option<i64>,
     pub failed_reason: Option<String>,
+    pub status: String,
+    // set once the client asked to finalize, no chunk is accepted past that
+    pub finalize_requested_at: Option<i64>,
+    pub finalize_step: Option<FinalizeStep>,
+    // read from the dataitem header in the first chunk
+    pub owner_address: Option<String>,
+}
+
+/// A session whose finalize was requested but never finished, see
+/// [`find_interrupted_finalizations`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingFinalize {
+    pub upload_id: String,
+    pub token: Option<String>,
+}
+
+/// A multipart session nobody sent a chunk to for a while, with what it has stored.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdleUpload {
+    pub upload_id: String,
+    pub upload_key: String,
+    pub s3_upload_id: String,
+    pub parts: i64,
+    pub stored_bytes: i64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -94,13 +287,18 @@ pub async fn create_upload_record(
     upload_id: &str,
     upload_key: &str,
     s3_upload_id: &str,
+    chunk_size: i64,
 ) -> Result<(), Error> {
+    let _timer = METRICS.backend("sqlite", "create_upload_record").start_timer();
     sqlx::query(
-        "INSERT INTO uploads (upload_id, upload_key, s3_upload_id, created_at) VALUES (?, ?, ?, ?)",
+        "INSERT INTO uploads (upload_id, upload_key, s3_upload_id, chunk_size, status, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
     )
     .bind(upload_id)
     .bind(upload_key)
     .bind(s3_upload_id)
+    .bind(chunk_size)
+    .bind(UPLOAD_ASSEMBLING)
+    .bind(chrono::Utc::now().timestamp())
     .bind(chrono::Utc::now().timestamp())
     .execute(pool)
     .await?;
@@ -109,10 +307,14 @@ pub async fn create_upload_record(
 }
 
 pub async fn get_upload(pool: &SqlitePool, upload_id: &str) -> Result<InFlightUpload, Error> {
-    let row = sqlx::query("SELECT upload_id, upload_key, s3_upload_id, chunk_size, failed_reason FROM uploads WHERE upload_id = ?")
-        .bind(upload_id)
-        .fetch_one(pool)
-        .await?;
+    let _timer = METRICS.backend("sqlite", "get_upload").start_timer();
+    let row = sqlx::query(
+        "SELECT upload_id, upload_key, s3_upload_id, chunk_size, failed_reason, status, \
+         finalize_requested_at, finalize_step, owner_address FROM uploads WHERE upload_id = ?",
+    )
+    .bind(upload_id)
+    .fetch_one(pool)
+    .await?;
 
     Ok(InFlightUpload {
         upload_id: row.get("upload_id"),
@@ -120,16 +322,26 @@ pub async fn get_upload(pool: &SqlitePool, upload_id: &str) -> Result<InFlightUp
         s3_upload_id: row.get("s3_upload_id"),
         chunk_size: row.get("chunk_size"),
         failed_reason: row.get("failed_reason"),
+        status: row.get("status"),
+        finalize_requested_at: row.get("finalize_requested_at"),
+        finalize_step: row
+            .get::<Option<String>, _>("finalize_step")
+            .as_deref()
+            .map(FinalizeStep::parse)
+            .transpose()?,
+        owner_address: row.get("owner_address"),
     })
 }
 
-pub async fn update_chunk_size(
+pub async fn mark_upload_failed(
     pool: &SqlitePool,
     upload_id: &str,
-    chunk_size: i64,
+    failed_reason: &str,
 ) -> Result<(), Error> {
-    sqlx::query("UPDATE uploads SET chunk_size = ? WHERE upload_id = ?")
-        .bind(chunk_size)
+    let _timer = METRICS.backend("sqlite", "mark_upload_failed").start_timer();
+    sqlx::query("UPDATE uploads SET status = ?, failed_reason = ? WHERE upload_id = ?")
+        .bind(UPLOAD_FAILED)
+        .bind(failed_reason)
         .bind(upload_id)
         .execute(pool)
         .await?;
@@ -137,6 +349,72 @@ pub async fn update_chunk_size(
     Ok(())
 }
 
+pub async fn set_upload_status(
+    pool: &SqlitePool,
+    upload_id: &str,
+    status: &str,
+) -> Result<(), Error> {
+    let _timer = METRICS.backend("sqlite", "set_upload_status").start_timer();
+    sqlx::query("UPDATE uploads SET status = ? WHERE upload_id = ?")
+        .bind(status)
+        .bind(upload_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn set_upload_owner(
+    pool: &SqlitePool,
+    upload_id: &str,
+    owner_address: &str,
+) -> Result<(), Error> {
+    let _timer = METRICS.backend("sqlite", "set_upload_owner").start_timer();
+    sqlx::query("UPDATE uploads SET owner_address = ? WHERE upload_id = ?")
+        .bind(owner_address)
+        .bind(upload_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn set_finalize_step(
+    pool: &SqlitePool,
+    upload_id: &str,
+    step: FinalizeStep,
+) -> Result<(), Error> {
+    let _timer = METRICS.backend("sqlite", "set_finalize_step").start_timer();
+    sqlx::query("UPDATE uploads SET finalize_step = ? WHERE upload_id = ?")
+        .bind(step.as_str())
+        .bind(upload_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+// Record the first finalize request of an open session, with the token it is finalized
+// under so a restart can resume it
+pub async fn request_finalize(
+    pool: &SqlitePool,
+    upload_id: &str,
+    token: &str,
+) -> Result<(), Error> {
+    let _timer = METRICS.backend("sqlite", "request_finalize").start_timer();
+    sqlx::query(
+        "UPDATE uploads SET token = ?, finalize_requested_at = ? \
+         WHERE upload_id = ? AND finalize_requested_at IS NULL AND failed_reason IS NULL",
+    )
+    .bind(token)
+    .bind(chrono::Utc::now().timestamp())
+    .bind(upload_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
 pub async fn save_chunk(
     pool: &SqlitePool,
     upload_id: &str,
@@ -144,6 +422,7 @@ pub async fn save_chunk(
     etag: &str,
     size: i64,
 ) -> Result<(), Error> {
+    let _timer = METRICS.backend("sqlite", "save_chunk").start_timer();
     sqlx::query(
         "INSERT OR REPLACE INTO chunks (upload_id, part_number, etag, size) VALUES (?, ?, ?, ?)",
     )
@@ -154,10 +433,17 @@ pub async fn save_chunk(
     .execute(pool)
     .await?;
 
+    sqlx::query("UPDATE uploads SET updated_at = ? WHERE upload_id = ?")
+        .bind(chrono::Utc::now().timestamp())
+        .bind(upload_id)
+        .execute(pool)
+        .await?;
+
     Ok(())
 }
 
 pub async fn get_chunks(pool: &SqlitePool, upload_id: &str) -> Result<Vec<ChunkInfo>, Error> {
+    let _timer = METRICS.backend("sqlite", "get_chunks").start_timer();
     let rows = sqlx::query(
         "SELECT part_number, size FROM chunks WHERE upload_id = ? ORDER BY part_number",
     )
@@ -173,15 +459,142 @@ pub async fn get_chunks(pool: &SqlitePool, upload_id: &str) -> Result<Vec<ChunkI
     Ok(chunks)
 }
 
-// Store completed upload information
+// Sessions queued for finalize before requested_before, in seconds, that neither finished nor
+// failed
+pub async fn find_interrupted_finalizations(
+    pool: &SqlitePool,
+    requested_before: i64,
+) -> Result<Vec<PendingFinalize>, Error> {
+    let _timer = METRICS.backend("sqlite", "find_interrupted_finalizations").start_timer();
+    let rows = sqlx::query(
+        "SELECT upload_id, token FROM uploads \
+         WHERE finalize_requested_at < ? AND failed_reason IS NULL \
+         ORDER BY finalize_requested_at",
+    )
+    .bind(requested_before)
+    .fetch_all(pool)
+    .await?;
+
+    let pending = rows
+        .into_iter()
+        .map(|row| PendingFinalize { upload_id: row.get("upload_id"), token: row.get("token") })
+        .collect();
+
+    Ok(pending)
+}
+
+// Sessions still taking chunks whose last chunk (or creation) is older than idle_before, in seconds
+pub async fn find_idle_uploads(
+    pool: &SqlitePool,
+    idle_before: i64,
+) -> Result<Vec<IdleUpload>, Error> {
+    let _timer = METRICS.backend("sqlite", "find_idle_uploads").start_timer();
+    let rows = sqlx::query(
+        "SELECT u.upload_id, u.upload_key, u.s3_upload_id, \
+         COUNT(c.part_number) AS parts, COALESCE(SUM(c.size), 0) AS stored_bytes \
+         FROM uploads u LEFT JOIN chunks c ON c.upload_id = u.upload_id \
+         WHERE u.failed_reason IS NULL AND u.finalize_requested_at IS NULL \
+         AND COALESCE(u.updated_at, u.created_at) < ? \
+         GROUP BY u.upload_id",
+    )
+    .bind(idle_before)
+    .fetch_all(pool)
+    .await?;
+
+    let uploads = rows
+        .into_iter()
+        .map(|row| IdleUpload {
+            upload_id: row.get("upload_id"),
+            upload_key: row.get("upload_key"),
+            s3_upload_id: row.get("s3_upload_id"),
+            parts: row.get("parts"),
+            stored_bytes: row.get("stored_bytes"),
+        })
+        .collect();
+
+    Ok(uploads)
+}
+
+// Count one more failed abort of an idle session, returns the failures so far
+pub async fn record_abort_attempt(pool: &SqlitePool, upload_id: &str) -> Result<i64, Error> {
+    let _timer = METRICS.backend("sqlite", "record_abort_attempt").start_timer();
+    let attempts = sqlx::query_scalar(
+        "UPDATE uploads SET abort_attempts = abort_attempts + 1 WHERE upload_id = ? \
+         RETURNING abort_attempts",
+    )
+    .bind(upload_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(attempts)
+}
+
+// Mark an aborted (expired or canceled) session failed and forget its chunks, the upload row
+// stays so later calls see the reason
+pub async fn fail_upload(
+    pool: &SqlitePool,
+    upload_id: &str,
+    failed_reason: &str,
+) -> Result<(), Error> {
+    let _timer = METRICS.backend("sqlite", "fail_upload").start_timer();
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE uploads SET status = ?, failed_reason = ? WHERE upload_id = ?")
+        .bind(UPLOAD_FAILED)
+        .bind(failed_reason)
+        .bind(upload_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM chunks WHERE upload_id = ?").bind(upload_id).execute(&mut *tx).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+// Mark a finalize that can't be resumed failed, dropping what it had recorded so the upload
+// doesn't show as completed
+pub async fn roll_back_finalize(
+    pool: &SqlitePool,
+    upload_id: &str,
+    failed_reason: &str,
+) -> Result<(), Error> {
+    let _timer = METRICS.backend("sqlite", "roll_back_finalize").start_timer();
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE uploads SET status = ?, failed_reason = ? WHERE upload_id = ?")
+        .bind(UPLOAD_FAILED)
+        .bind(failed_reason)
+        .bind(upload_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM chunks WHERE upload_id = ?").bind(upload_id).execute(&mut *tx).await?;
+    sqlx::query("DELETE FROM completed_uploads WHERE upload_id = ?")
+        .bind(upload_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+// Drop the in-flight rows of a finalized upload
+pub async fn delete_upload_records(pool: &SqlitePool, upload_id: &str) -> Result<(), Error> {
+    let _timer = METRICS.backend("sqlite", "delete_upload_records").start_timer();
+    sqlx::query("DELETE FROM chunks WHERE upload_id = ?").bind(upload_id).execute(pool).await?;
+
+    sqlx::query("DELETE FROM uploads WHERE upload_id = ?").bind(upload_id).execute(pool).await?;
+
+    Ok(())
+}
+
+// Store completed upload information, kept as first stored when a resumed finalize repeats it
 pub async fn store_completed_upload(
     pool: &SqlitePool,
     upload_id: &str,
     dataitem_id: &str,
     owner_address: Option<&str>,
 ) -> Result<(), Error> {
+    let _timer = METRICS.backend("sqlite", "store_completed_upload").start_timer();
     sqlx::query(
-        "INSERT INTO completed_uploads (upload_id, dataitem_id, owner_address, finalized_at) VALUES (?, ?, ?, ?)"
+        "INSERT OR IGNORE INTO completed_uploads (upload_id, dataitem_id, owner_address, finalized_at) VALUES (?, ?, ?, ?)"
     )
     .bind(upload_id)
     .bind(dataitem_id)
@@ -198,6 +611,7 @@ pub async fn get_completed_upload(
     pool: &SqlitePool,
     upload_id: &str,
 ) -> Result<(String, Option<String>), Error> {
+    let _timer = METRICS.backend("sqlite", "get_completed_upload").start_timer();
     let row =
         sqlx::query("SELECT dataitem_id, owner_address FROM completed_uploads WHERE upload_id = ?")
             .bind(upload_id)
@@ -206,3 +620,101 @@ pub async fn get_completed_upload(
 
     Ok((row.get("dataitem_id"), row.get("owner_address")))
 }
+
+// Store a signed receipt unless the dataitem already has one, returns the one kept
+pub async fn store_receipt(
+    pool: &SqlitePool,
+    dataitem_id: &str,
+    receipt: &str,
+) -> Result<String, Error> {
+    let _timer = METRICS.backend("sqlite", "store_receipt").start_timer();
+    sqlx::query(
+        "INSERT OR IGNORE INTO receipts (dataitem_id, receipt, created_at) VALUES (?, ?, ?)",
+    )
+    .bind(dataitem_id)
+    .bind(receipt)
+    .bind(chrono::Utc::now().timestamp())
+    .execute(pool)
+    .await?;
+
+    let stored = sqlx::query_scalar("SELECT receipt FROM receipts WHERE dataitem_id = ?")
+        .bind(dataitem_id)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(stored)
+}
+
+pub async fn get_receipt(pool: &SqlitePool, dataitem_id: &str) -> Result<Option<String>, Error> {
+    let _timer = METRICS.backend("sqlite", "get_receipt").start_timer();
+    let receipt = sqlx::query_scalar("SELECT receipt FROM receipts WHERE dataitem_id = ?")
+        .bind(dataitem_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(receipt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+    use sha2::{Digest, Sha256};
+
+    #[tokio::test]
+    async fn missing_columns_are_added_once() {
+        let pool = test_pool().await;
+        sqlx::query("CREATE TABLE sessions (id TEXT PRIMARY KEY)").execute(&pool).await.unwrap();
+        sqlx::query("INSERT INTO sessions (id) VALUES ('a')").execute(&pool).await.unwrap();
+
+        let ddl = "INTEGER NOT NULL DEFAULT 0";
+        assert!(add_column_if_missing(&pool, "sessions", "attempts", ddl).await.unwrap());
+        assert!(!add_column_if_missing(&pool, "sessions", "attempts", ddl).await.unwrap());
+
+        let attempts: i64 =
+            sqlx::query_scalar("SELECT attempts FROM sessions").fetch_one(&pool).await.unwrap();
+        assert_eq!(attempts, 0);
+    }
+
+    #[tokio::test]
+    async fn legacy_arweave_owners_are_rewritten() {
+        let pool = test_pool().await;
+        sqlx::query("CREATE TABLE owners (owner TEXT PRIMARY KEY, rule TEXT NOT NULL)")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let modulus = |byte: u8| URL_SAFE_NO_PAD.encode([byte; 512]);
+        let address = |byte: u8| URL_SAFE_NO_PAD.encode(Sha256::digest([byte; 512]));
+        for (owner, rule) in [
+            (modulus(1), "legacy"),
+            (modulus(2), "legacy"),
+            // stored again under its address, which wins
+            (address(2), "current"),
+            ("0x1e42d9d1bbec0a7a2c2f2b1d0d3ae2a5a8c0b9f1".to_string(), "current"),
+        ] {
+            sqlx::query("INSERT INTO owners (owner, rule) VALUES (?, ?)")
+                .bind(owner)
+                .bind(rule)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        // twice, a migrated table is left as is
+        for _ in 0..2 {
+            migrate_arweave_owner_addresses(&pool, "owners", "owner").await.unwrap();
+        }
+
+        let mut owners: Vec<(String, String)> =
+            sqlx::query_as("SELECT owner, rule FROM owners").fetch_all(&pool).await.unwrap();
+        owners.sort();
+        let mut expected = vec![
+            (address(1), "legacy".to_string()),
+            (address(2), "current".to_string()),
+            ("0x1e42d9d1bbec0a7a2c2f2b1d0d3ae2a5a8c0b9f1".to_string(), "current".to_string()),
+        ];
+        expected.sort();
+        assert_eq!(owners, expected);
+    }
+}
@@ -0,0 +1,482 @@
+use crate::{
+    api::{handlers::upload_error_response, receipts::issue_receipt},
+    arbundles::{token::Token, verify::signature_type_name},
+    db::{find_interrupted_finalizations, mark_upload_failed},
+    metrics::METRICS,
+    state::AppState,
+    storage::{FinalizeAborted, abandon_finalize, finalize_multipart_upload, is_rejection},
+};
+use anyhow::{Error, anyhow};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{Semaphore, mpsc};
+use tracing::{Span, error, field::Empty, info, instrument, warn};
+
+// finalize requests waiting for a worker before enqueueing waits
+const QUEUE_CAPACITY: usize = 1024;
+
+/// A multipart session the client asked to finalize.
+#[derive(Debug, Clone)]
+pub struct FinalizeJob {
+    pub token: Token,
+    pub upload_id: String,
+    // runs that already failed on a backend error
+    pub attempts: u32,
+}
+
+impl FinalizeJob {
+    pub fn new(token: Token, upload_id: String) -> Self {
+        Self { token, upload_id, attempts: 0 }
+    }
+}
+
+/// Hands finalize requests to the background workers, see [`spawn_workers`].
+#[derive(Clone)]
+pub struct FinalizeQueue {
+    sender: mpsc::Sender<FinalizeJob>,
+    // sessions queued or being finalized, so a session is never run twice at once
+    pending: Arc<Mutex<HashSet<String>>>,
+}
+
+impl FinalizeQueue {
+    pub fn new() -> (Self, mpsc::Receiver<FinalizeJob>) {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        (Self { sender, pending: Arc::default() }, receiver)
+    }
+
+    /// Queue a session unless it is already queued or being finalized.
+    pub async fn enqueue(&self, job: FinalizeJob) -> Result<(), Error> {
+        if !self.pending.lock().unwrap().insert(job.upload_id.clone()) {
+            return Ok(());
+        }
+        let upload_id = job.upload_id.clone();
+        self.sender.send(job).await.map_err(|_| {
+            self.release(&upload_id);
+            anyhow!("finalize workers are not running")
+        })
+    }
+
+    // a retried job keeps its pending entry through the backoff, nothing else queues it meanwhile
+    async fn requeue(&self, job: FinalizeJob) {
+        let upload_id = job.upload_id.clone();
+        if self.sender.send(job).await.is_err() {
+            self.release(&upload_id);
+        }
+    }
+
+    fn is_pending(&self, upload_id: &str) -> bool {
+        self.pending.lock().unwrap().contains(upload_id)
+    }
+
+    fn release(&self, upload_id: &str) {
+        self.pending.lock().unwrap().remove(upload_id);
+    }
+}
+
+/// Run queued finalize jobs, at most `workers` at a time, for as long as the process runs.
+pub fn spawn_workers(state: AppState, mut jobs: mpsc::Receiver<FinalizeJob>, workers: usize) {
+    let permits = Arc::new(Semaphore::new(workers));
+    tokio::spawn(async move {
+        while let Some(job) = jobs.recv().await {
+            let Ok(permit) = permits.clone().acquire_owned().await else {
+                break;
+            };
+            let state = state.clone();
+            tokio::spawn(async move {
+                let upload_id = job.upload_id.clone();
+                let retry = run_job(&state, job).await;
+                drop(permit);
+                match retry {
+                    Some((job, backoff)) => {
+                        tokio::time::sleep(backoff).await;
+                        state.finalize.requeue(job).await;
+                    }
+                    None => state.finalize.release(&upload_id),
+                }
+            });
+        }
+    });
+}
+
+/// Queue again the finalizations a restart interrupted. Each resumes after its last recorded
+/// step, or is rolled back and marked failed when it can't be finished.
+pub async fn recover(state: &AppState) -> Result<(), Error> {
+    let interrupted = find_interrupted_finalizations(&state.db_pool, i64::MAX).await?;
+    let count = interrupted.len();
+    for pending in interrupted {
+        let Some(token) = pending.token.as_deref().and_then(|token| token.parse::<Token>().ok())
+        else {
+            warn!(upload_id = pending.upload_id, "interrupted finalize without a valid token");
+            mark_upload_failed(&state.db_pool, &pending.upload_id, "finalize token is missing")
+                .await?;
+            continue;
+        };
+        state.finalize.enqueue(FinalizeJob::new(token, pending.upload_id)).await?;
+    }
+
+    if count > 0 {
+        info!(sessions = count, "resuming interrupted multipart finalizations");
+    }
+    Ok(())
+}
+
+/// Fail the sessions whose finalize was requested more than `timeout` ago and that no worker
+/// holds, queued, running or waiting to retry. They were lost some other way than a restart,
+/// which [`recover`] covers. A session whose dataitem is already copied to its final key is
+/// queued again instead, only finishing it settles it.
+pub async fn sweep_stuck_finalizations(state: &AppState, timeout: Duration) -> Result<u64, Error> {
+    let requested_before = chrono::Utc::now().timestamp() - timeout.as_secs() as i64;
+    let failed_reason = format!("finalize did not complete within {}s", timeout.as_secs());
+
+    let mut failed = 0;
+    for stuck in find_interrupted_finalizations(&state.db_pool, requested_before).await? {
+        if state.finalize.is_pending(&stuck.upload_id) {
+            continue;
+        }
+        if abandon_finalize(state, &stuck.upload_id, &failed_reason).await? {
+            METRICS.multipart_sessions_total.with_label_values(&["failed"]).inc();
+            failed += 1;
+            continue;
+        }
+
+        let Some(token) = stuck.token.as_deref().and_then(|token| token.parse::<Token>().ok())
+        else {
+            warn!(upload_id = stuck.upload_id, "stuck finalize without a valid token");
+            continue;
+        };
+        warn!(upload_id = stuck.upload_id, "resuming stuck finalize of a stored dataitem");
+        state.finalize.enqueue(FinalizeJob::new(token, stuck.upload_id)).await?;
+    }
+
+    Ok(failed)
+}
+
+/// Sweep stuck finalizations every `interval` for as long as the process runs.
+pub fn spawn_stuck_sweep(state: AppState, timeout: Duration, interval: Duration) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(interval);
+        loop {
+            tick.tick().await;
+            match sweep_stuck_finalizations(&state, timeout).await {
+                Ok(0) => {}
+                Ok(sessions) => warn!(sessions, "failed stuck multipart finalizations"),
+                Err(e) => error!(error = ?e, "sweeping stuck multipart finalizations failed"),
+            }
+        }
+    });
+}
+
+/// Assemble and validate the session, then issue its receipt. The session moves from
+/// ASSEMBLING to VALIDATING once its parts are joined and ends FINALIZED, or FAILED with
+/// the reason its status route reports. A backend error is retried with an exponential
+/// backoff, resuming after the last recorded step, and the session is abandoned once
+/// `finalize_max_attempts` runs failed, unless its dataitem was already copied to its final
+/// key: that one keeps being retried at the longest backoff. Returns the job to queue again
+/// and when.
+#[instrument(skip_all, fields(token = %job.token, upload_id = %job.upload_id, dataitem_id = Empty))]
+async fn run_job(state: &AppState, job: FinalizeJob) -> Option<(FinalizeJob, Duration)> {
+    let FinalizeJob { token, ref upload_id, attempts } = job;
+    match finalize_multipart_upload(state, token, upload_id).await {
+        Ok(stored) => {
+            Span::current().record("dataitem_id", stored.id.as_str());
+            METRICS.multipart_sessions_total.with_label_values(&["finalized"]).inc();
+            METRICS.record_upload(
+                "chunks",
+                token,
+                signature_type_name(stored.signature_type),
+                "ok",
+                stored.size,
+            );
+
+            // a receipt that fails to be signed here is issued by the status route instead
+            if let Err(e) = issue_receipt(
+                &state.db_pool,
+                &state.config,
+                &state.receipt_signer,
+                stored.id,
+                stored.owner,
+            )
+            .await
+            {
+                error!(error = ?e, "issuing receipt failed");
+            }
+            None
+        }
+        // refused dataitems are marked failed with the reason, aborted ones are settled already
+        Err(e) if is_rejection(&e) || e.downcast_ref::<FinalizeAborted>().is_some() => {
+            warn!(error = ?e, "finalizing upload failed");
+            METRICS.multipart_sessions_total.with_label_values(&["failed"]).inc();
+            let (_, outcome, _) = upload_error_response(&e, "Failed to finalize upload");
+            METRICS.record_upload("chunks", token, "unknown", outcome, 0);
+            None
+        }
+        Err(e) => {
+            let attempts = attempts + 1;
+            let max_attempts = state.config.finalize_max_attempts;
+            let backoff = Duration::from_secs(state.config.finalize_retry_backoff_secs)
+                * 2u32.saturating_pow(attempts.min(max_attempts) - 1);
+            if attempts < max_attempts {
+                warn!(error = ?e, attempts, ?backoff, "finalizing upload failed, retrying");
+                METRICS.multipart_sessions_total.with_label_values(&["retried"]).inc();
+                return Some((FinalizeJob { attempts, ..job }, backoff));
+            }
+
+            let failed_reason = format!("finalize failed after {attempts} attempts");
+            match abandon_finalize(state, upload_id, &failed_reason).await {
+                Ok(true) => {
+                    error!(error = ?e, attempts, "finalizing upload failed, giving up");
+                    METRICS.multipart_sessions_total.with_label_values(&["failed"]).inc();
+                    METRICS.record_upload("chunks", token, "unknown", "error", 0);
+                    None
+                }
+                Ok(false) => {
+                    error!(error = ?e, attempts, ?backoff, "finalizing copied dataitem failed");
+                    METRICS.multipart_sessions_total.with_label_values(&["retried"]).inc();
+                    Some((FinalizeJob { attempts, ..job }, backoff))
+                }
+                Err(abandon_error) => {
+                    error!(error = ?e, ?abandon_error, "abandoning failed finalize failed");
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::multipart_uploads::finalize_multipart_upload_handler,
+        db::{FinalizeStep, get_completed_upload, get_receipt, get_upload},
+        storage::{flaky::FlakyStorage, fs::FsStorage},
+    };
+    use axum::{
+        extract::{Path, State},
+        http::StatusCode,
+    };
+
+    const ED25519: &[u8] = include_bytes!("../arbundles/testdata/ed25519.ans104");
+
+    struct Fixture {
+        state: AppState,
+        jobs: mpsc::Receiver<FinalizeJob>,
+        storage: Arc<FlakyStorage>,
+        _root: tempfile::TempDir,
+    }
+
+    async fn fixture() -> Fixture {
+        let root = tempfile::tempdir().unwrap();
+        let storage = Arc::new(FlakyStorage::new(FsStorage::new(root.path()).await.unwrap()));
+        let (state, jobs) = AppState::for_tests(storage.clone()).await;
+        Fixture { state, jobs, storage, _root: root }
+    }
+
+    // a finalize that stopped after recording the quota use, its dataitem already copied
+    async fn copied(fixture: &Fixture, upload_id: &str) {
+        fixture.state.finalize_requested(upload_id, Token::Ed25519, ED25519, 2).await;
+        fixture.storage.fail_next("delete_object");
+        let job = FinalizeJob::new(Token::Ed25519, upload_id.to_string());
+        assert!(run_job(&fixture.state, job).await.is_some());
+    }
+
+    // the receipt issued for a finalized session
+    async fn receipt(fixture: &Fixture, upload_id: &str) -> Option<String> {
+        let pool = &fixture.state.db_pool;
+        let (dataitem_id, _) = get_completed_upload(pool, upload_id).await.unwrap();
+        get_receipt(pool, &dataitem_id).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn finalized_sessions_get_their_receipt() {
+        let fixture = fixture().await;
+        fixture.state.finalize_requested("upload", Token::Ed25519, ED25519, 2).await;
+
+        let job = FinalizeJob::new(Token::Ed25519, "upload".to_string());
+        assert!(run_job(&fixture.state, job).await.is_none());
+
+        // moved to the completed uploads, indexed and with its receipt
+        let pool = &fixture.state.db_pool;
+        assert!(get_upload(pool, "upload").await.is_err());
+        let (dataitem_id, _) = get_completed_upload(pool, "upload").await.unwrap();
+        let receipt: serde_json::Value =
+            serde_json::from_str(&receipt(&fixture, "upload").await.unwrap()).unwrap();
+        assert_eq!(receipt["id"], dataitem_id.as_str());
+        let indexed: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM dataitem_tags WHERE dataitem_id = ?")
+                .bind(&dataitem_id)
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        assert!(indexed > 0);
+        let storage = &fixture.state.storage;
+        let stored = storage.head_object(&storage.dataitem_key(&dataitem_id)).await.unwrap();
+        assert_eq!(stored, Some(ED25519.len() as u64));
+    }
+
+    #[tokio::test]
+    async fn rejected_dataitems_fail_with_the_reason() {
+        let mut tampered = ED25519.to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        for (token, dataitem, reason) in [
+            (Token::Ed25519, tampered.as_slice(), "invalid dataitem: "),
+            (
+                Token::Ethereum,
+                ED25519,
+                "invalid dataitem: signature type 2 can't be uploaded with token ethereum",
+            ),
+        ] {
+            let fixture = fixture().await;
+            fixture.state.finalize_requested("upload", token, dataitem, 2).await;
+
+            // refused for good, not retried
+            let job = FinalizeJob::new(token, "upload".to_string());
+            assert!(run_job(&fixture.state, job).await.is_none());
+
+            let upload = get_upload(&fixture.state.db_pool, "upload").await.unwrap();
+            assert_eq!(upload.status, "FAILED");
+            let failed_reason = upload.failed_reason.unwrap();
+            assert!(failed_reason.starts_with(reason), "{failed_reason}");
+            let storage = &fixture.state.storage;
+            assert_eq!(storage.head_object(&upload.upload_key).await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn backend_errors_are_retried() {
+        let fixture = fixture().await;
+        fixture.state.finalize_requested("upload", Token::Ed25519, ED25519, 2).await;
+
+        fixture.storage.fail_next("copy_object");
+        let job = FinalizeJob::new(Token::Ed25519, "upload".to_string());
+        let (job, backoff) = run_job(&fixture.state, job).await.unwrap();
+        let first_backoff = Duration::from_secs(fixture.state.config.finalize_retry_backoff_secs);
+        assert_eq!((job.attempts, backoff), (1, first_backoff));
+
+        // still in progress, with what it got through so far
+        let upload = get_upload(&fixture.state.db_pool, "upload").await.unwrap();
+        assert_eq!(upload.status, "VALIDATING");
+        assert_eq!(upload.failed_reason, None);
+        assert_eq!(upload.finalize_step, Some(FinalizeStep::Validated));
+
+        fixture.storage.fail_next("copy_object");
+        let (job, backoff) = run_job(&fixture.state, job).await.unwrap();
+        assert_eq!((job.attempts, backoff), (2, first_backoff * 2));
+
+        assert!(run_job(&fixture.state, job).await.is_none());
+        assert!(receipt(&fixture, "upload").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn interrupted_finalizes_resume_on_recover() {
+        let mut fixture = fixture().await;
+        // the process died right after storage assembled the parts, before the step was recorded
+        fixture.state.finalize_requested("assembled", Token::Ed25519, ED25519, 2).await;
+        let upload = get_upload(&fixture.state.db_pool, "assembled").await.unwrap();
+        let storage = &fixture.state.storage;
+        let parts = storage.list_parts(&upload.upload_key, &upload.s3_upload_id).await.unwrap();
+        storage.complete_multipart(&upload.upload_key, &upload.s3_upload_id, parts).await.unwrap();
+        // and right after the quota use was recorded
+        copied(&fixture, "recorded").await;
+
+        recover(&fixture.state).await.unwrap();
+        let mut resumed = Vec::new();
+        while let Ok(job) = fixture.jobs.try_recv() {
+            resumed.push(job.upload_id.clone());
+            assert!(run_job(&fixture.state, job).await.is_none());
+        }
+        resumed.sort();
+        assert_eq!(resumed, ["assembled", "recorded"]);
+        for upload_id in resumed {
+            assert!(receipt(&fixture, &upload_id).await.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn copied_dataitems_are_retried_past_max_attempts() {
+        let fixture = fixture().await;
+        let max_attempts = fixture.state.config.finalize_max_attempts;
+        let longest_backoff = Duration::from_secs(fixture.state.config.finalize_retry_backoff_secs)
+            * 2u32.pow(max_attempts - 1);
+        copied(&fixture, "upload").await;
+
+        let mut job = FinalizeJob::new(Token::Ed25519, "upload".to_string());
+        for attempts in [max_attempts - 1, max_attempts, max_attempts + 1] {
+            job.attempts = attempts;
+            fixture.storage.fail_next("delete_object");
+            let (retried, backoff) = run_job(&fixture.state, job.clone()).await.unwrap();
+            assert_eq!((retried.attempts, backoff), (attempts + 1, longest_backoff));
+            let upload = get_upload(&fixture.state.db_pool, "upload").await.unwrap();
+            assert_eq!(upload.failed_reason, None);
+        }
+
+        assert!(run_job(&fixture.state, job).await.is_none());
+        assert!(receipt(&fixture, "upload").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn finalizes_before_the_copy_give_up_after_max_attempts() {
+        let fixture = fixture().await;
+        let max_attempts = fixture.state.config.finalize_max_attempts;
+        fixture.state.finalize_requested("upload", Token::Ed25519, ED25519, 2).await;
+
+        let mut job = FinalizeJob::new(Token::Ed25519, "upload".to_string());
+        job.attempts = max_attempts - 1;
+        fixture.storage.fail_next("complete_multipart");
+        assert!(run_job(&fixture.state, job).await.is_none());
+
+        let upload = get_upload(&fixture.state.db_pool, "upload").await.unwrap();
+        assert_eq!(upload.status, "FAILED");
+        let failed_reason = format!("finalize failed after {max_attempts} attempts");
+        assert_eq!(upload.failed_reason, Some(failed_reason));
+    }
+
+    #[tokio::test]
+    async fn stuck_copied_finalizes_are_queued_again() {
+        let mut fixture = fixture().await;
+        copied(&fixture, "copied").await;
+        fixture.state.finalize_requested("assembling", Token::Ed25519, ED25519, 2).await;
+        sqlx::query("UPDATE uploads SET finalize_requested_at = finalize_requested_at - 120")
+            .execute(&fixture.state.db_pool)
+            .await
+            .unwrap();
+
+        let failed = sweep_stuck_finalizations(&fixture.state, Duration::from_secs(60)).await;
+        assert_eq!(failed.unwrap(), 1);
+        let upload = get_upload(&fixture.state.db_pool, "assembling").await.unwrap();
+        assert_eq!(upload.status, "FAILED");
+
+        let job = fixture.jobs.try_recv().unwrap();
+        assert_eq!(job.upload_id, "copied");
+        assert!(fixture.jobs.try_recv().is_err());
+        assert!(run_job(&fixture.state, job).await.is_none());
+        assert!(receipt(&fixture, "copied").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn finalizing_again_returns_the_same_receipt() {
+        let mut fixture = fixture().await;
+        fixture.state.finalize_requested("upload", Token::Ed25519, ED25519, 2).await;
+        let finalize = || {
+            let path = Path((Token::Ed25519, "upload".to_string()));
+            finalize_multipart_upload_handler(path, State(fixture.state.clone()))
+        };
+
+        assert_eq!(finalize().await.unwrap().status(), StatusCode::ACCEPTED);
+        let job = fixture.jobs.try_recv().unwrap();
+        assert!(run_job(&fixture.state, job).await.is_none());
+        let stored = receipt(&fixture, "upload").await.unwrap();
+
+        for _ in 0..2 {
+            let response = finalize().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let returned: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(returned, serde_json::from_str::<serde_json::Value>(&stored).unwrap());
+        }
+        assert!(fixture.jobs.try_recv().is_err());
+    }
+}
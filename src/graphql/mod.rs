@@ -0,0 +1,337 @@
+use crate::indexing::{DataItemCursor, DataItemQuery, IndexDisabled, IndexedEntry, Indexer};
+
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, Enum, ID, InputObject, Object, Schema, SimpleObject,
+};
+use std::sync::Arc;
+use tracing::error;
+
+// page sizes as on the Arweave gateways
+const DEFAULT_PAGE_SIZE: i32 = 10;
+const MAX_PAGE_SIZE: i32 = 100;
+
+pub type GatewaySchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+/// The Arweave gateway `transactions` / `transaction` queries over the dataitem tag index.
+pub fn build_schema(indexer: Arc<dyn Indexer>) -> GatewaySchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .data(indexer)
+        .limit_depth(16)
+        .finish()
+}
+
+/// Dataitems are never settled on Arweave here, so there are no heights to sort by:
+/// both orders go by index time instead.
+#[derive(Enum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    HeightAsc,
+    #[default]
+    HeightDesc,
+}
+
+#[derive(Enum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TagOperator {
+    #[default]
+    Eq,
+    Neq,
+}
+
+#[derive(InputObject, Debug)]
+pub struct TagFilter {
+    name: String,
+    values: Vec<String>,
+    #[graphql(default)]
+    op: TagOperator,
+}
+
+#[derive(InputObject, Debug)]
+pub struct BlockFilter {
+    min: Option<i32>,
+    max: Option<i32>,
+}
+
+#[derive(SimpleObject)]
+pub struct TransactionConnection {
+    page_info: PageInfo,
+    edges: Vec<TransactionEdge>,
+}
+
+#[derive(SimpleObject)]
+pub struct PageInfo {
+    has_next_page: bool,
+}
+
+#[derive(SimpleObject)]
+pub struct TransactionEdge {
+    cursor: String,
+    node: Transaction,
+}
+
+#[derive(SimpleObject)]
+pub struct Transaction {
+    id: ID,
+    // empty for dataitems without an anchor, or indexed before anchors were
+    anchor: String,
+    signature: String,
+    recipient: String,
+    owner: Owner,
+    fee: Amount,
+    quantity: Amount,
+    data: MetaData,
+    tags: Vec<Tag>,
+    block: Option<Block>,
+    bundled_in: Option<Bundle>,
+}
+
+#[derive(SimpleObject)]
+pub struct Owner {
+    address: String,
+    // base64url public key
+    key: String,
+}
+
+#[derive(SimpleObject, Default)]
+pub struct Amount {
+    winston: String,
+    ar: String,
+}
+
+#[derive(SimpleObject)]
+pub struct MetaData {
+    size: String,
+    #[graphql(name = "type")]
+    content_type: Option<String>,
+}
+
+#[derive(SimpleObject)]
+pub struct Tag {
+    name: String,
+    value: String,
+}
+
+#[derive(SimpleObject)]
+pub struct Block {
+    id: ID,
+    timestamp: i32,
+    height: i32,
+    previous: ID,
+}
+
+#[derive(SimpleObject)]
+pub struct Bundle {
+    id: ID,
+}
+
+impl From<IndexedEntry> for Transaction {
+    fn from(entry: IndexedEntry) -> Self {
+        let zero = || Amount { winston: "0".to_string(), ar: "0.000000000000".to_string() };
+        Self {
+            id: ID(entry.id),
+            anchor: entry.anchor.unwrap_or_default(),
+            signature: entry.signature.unwrap_or_default(),
+            recipient: entry.target.unwrap_or_default(),
+            owner: Owner {
+                address: entry.owner.unwrap_or_default(),
+                key: entry.owner_key.unwrap_or_default(),
+            },
+            fee: zero(),
+            quantity: zero(),
+            data: MetaData {
+                size: entry.size.unwrap_or_default().to_string(),
+                content_type: Some(entry.content_type).filter(|t| !t.is_empty()),
+            },
+            tags: entry.tags.into_iter().map(|(name, value)| Tag { name, value }).collect(),
+            block: None,
+            bundled_in: None,
+        }
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn transaction(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+    ) -> async_graphql::Result<Option<Transaction>> {
+        let query = DataItemQuery { ids: vec![id.0], limit: 1, ..Default::default() };
+        let entries = query_index(ctx, &query).await?;
+        Ok(entries.into_iter().next().map(Transaction::from))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn transactions(
+        &self,
+        ctx: &Context<'_>,
+        ids: Option<Vec<ID>>,
+        owners: Option<Vec<String>>,
+        recipients: Option<Vec<String>>,
+        tags: Option<Vec<TagFilter>>,
+        bundled_in: Option<Vec<ID>>,
+        block: Option<BlockFilter>,
+        #[graphql(default = 10)] first: i32,
+        after: Option<String>,
+        #[graphql(default)] sort: SortOrder,
+    ) -> async_graphql::Result<TransactionConnection> {
+        let empty = || TransactionConnection {
+            page_info: PageInfo { has_next_page: false },
+            edges: Vec::new(),
+        };
+        // nothing here is bundled or in a block yet
+        if bundled_in.is_some_and(|ids| !ids.is_empty())
+            || block.is_some_and(|b| b.min.is_some() || b.max.is_some())
+        {
+            return Ok(empty());
+        }
+
+        let tags = tags.unwrap_or_default();
+        if tags.iter().any(|filter| filter.op == TagOperator::Neq) {
+            return Err("NEQ tag filters are not supported".into());
+        }
+
+        let after = after
+            .map(|cursor| DataItemCursor::decode(&cursor))
+            .transpose()
+            .map_err(|_| async_graphql::Error::new("invalid cursor"))?;
+        let first = if first <= 0 { DEFAULT_PAGE_SIZE } else { first.min(MAX_PAGE_SIZE) };
+
+        let query = DataItemQuery {
+            ids: ids.unwrap_or_default().into_iter().map(|id| id.0).collect(),
+            owners: owners.unwrap_or_default(),
+            targets: recipients.unwrap_or_default(),
+            tags: tags
+                .into_iter()
+                .map(|filter| crate::indexing::TagFilter {
+                    name: filter.name,
+                    values: filter.values,
+                })
+                .collect(),
+            after,
+            // one more than asked to tell whether there is a next page
+            limit: first as usize + 1,
+            ascending: sort == SortOrder::HeightAsc,
+            ..Default::default()
+        };
+
+        let mut entries = query_index(ctx, &query).await?;
+        let has_next_page = entries.len() > first as usize;
+        entries.truncate(first as usize);
+
+        Ok(TransactionConnection {
+            page_info: PageInfo { has_next_page },
+            edges: entries
+                .into_iter()
+                .map(|entry| TransactionEdge {
+                    cursor: entry.cursor().encode(),
+                    node: Transaction::from(entry),
+                })
+                .collect(),
+        })
+    }
+}
+
+async fn query_index(
+    ctx: &Context<'_>,
+    query: &DataItemQuery,
+) -> async_graphql::Result<Vec<IndexedEntry>> {
+    let indexer = ctx.data::<Arc<dyn Indexer>>()?;
+    indexer.query_dataitems(query).await.map_err(|e| {
+        if e.downcast_ref::<IndexDisabled>().is_some() {
+            return async_graphql::Error::new(e.to_string());
+        }
+        error!(error = ?e, "querying the dataitem index failed");
+        async_graphql::Error::new("querying the dataitem index failed")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        arbundles::token::Token,
+        indexing::{IndexedDataItem, sqlite::SqliteIndexer},
+    };
+    use serde_json::json;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn indexed_schema() -> GatewaySchema {
+        // a single connection, every in-memory connection is a database of its own
+        let pool =
+            SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+        let indexer: Arc<dyn Indexer> = Arc::new(SqliteIndexer::new(pool).await.unwrap());
+        for (id, owner, app_name, anchor) in [
+            ("item-a", "owner-a", "Foo", Some("anchor-a")),
+            ("item-b", "owner-a", "Bar", None),
+            ("item-c", "owner-c", "Foo", None),
+        ] {
+            indexer
+                .index_dataitem(&IndexedDataItem {
+                    id,
+                    content_type: "text/plain",
+                    tags: &[("App-Name".to_string(), app_name.to_string())],
+                    size: 1024,
+                    owner: Some(owner),
+                    owner_key: Some(&format!("{owner}-key")),
+                    signature: Some(&format!("{id}-signature")),
+                    anchor,
+                    target: None,
+                    token: Token::Arweave,
+                })
+                .await
+                .unwrap();
+        }
+        build_schema(indexer)
+    }
+
+    #[tokio::test]
+    async fn transactions_filter_by_owners_and_tags() {
+        let schema = indexed_schema().await;
+        let response = schema
+            .execute(
+                r#"query {
+                    transactions(owners: ["owner-a"], tags: [{ name: "App-Name", values: ["Foo"] }]) {
+                        pageInfo { hasNextPage }
+                        edges { node { id anchor signature owner { address key } data { size type } } }
+                    }
+                }"#,
+            )
+            .await;
+
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        assert_eq!(
+            response.data.into_json().unwrap(),
+            json!({
+                "transactions": {
+                    "pageInfo": { "hasNextPage": false },
+                    "edges": [{
+                        "node": {
+                            "id": "item-a",
+                            "anchor": "anchor-a",
+                            "signature": "item-a-signature",
+                            "owner": { "address": "owner-a", "key": "owner-a-key" },
+                            "data": { "size": "1024", "type": "text/plain" },
+                        }
+                    }]
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn transaction_without_an_anchor_has_an_empty_one() {
+        let schema = indexed_schema().await;
+        let response = schema
+            .execute(r#"query { transaction(id: "item-b") { anchor owner { address key } } }"#)
+            .await;
+
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        assert_eq!(
+            response.data.into_json().unwrap(),
+            json!({
+                "transaction": { "anchor": "", "owner": { "address": "owner-a", "key": "owner-a-key" } }
+            })
+        );
+    }
+}
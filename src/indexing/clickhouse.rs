@@ -0,0 +1,425 @@
+use crate::{
+    indexing::{
+        DataItemQuery, IndexedDataItem, IndexedEntry, Indexer, collect_entries, dataitem_keys_sql,
+        id_placeholders, tags_to_index,
+    },
+    metrics::METRICS,
+};
+
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use chrono::Utc;
+use clickhouse::{Client, Row, inserter::Inserter};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+// dataitems waiting for the inserter task before index_dataitem starts to wait
+const PENDING_DATAITEMS: usize = 1024;
+
+const TABLE_DDL: &str = r#"
+CREATE TABLE IF NOT EXISTS dataitem_tags
+(
+    dataitem_id String,
+    content_type String,
+    created_at   DateTime64(3, 'UTC'),
+    dataitem_size Nullable(UInt64),
+    owner Nullable(String),
+    target Nullable(String),
+    token LowCardinality(String) DEFAULT '',
+    owner_key Nullable(String),
+    signature Nullable(String),
+    anchor Nullable(String),
+    tag_key      String,
+    tag_value    String
+)
+ENGINE = ReplacingMergeTree(created_at)
+ORDER BY (tag_key, tag_value, dataitem_id);
+"#;
+
+#[derive(Debug, Deserialize, Row)]
+struct ExistingTable {
+    engine: String,
+}
+
+#[derive(Debug, Deserialize, Row)]
+struct RowCount {
+    rows: u64,
+}
+
+/// One `dataitem_tags` row, written and read with RowBinary in column order.
+#[derive(Debug, Serialize, Deserialize, Row)]
+struct TagRow {
+    dataitem_id: String,
+    content_type: String,
+    // DateTime64(3) travels as milliseconds since the epoch
+    created_at: i64,
+    dataitem_size: Option<u64>,
+    owner: Option<String>,
+    target: Option<String>,
+    token: String,
+    owner_key: Option<String>,
+    signature: Option<String>,
+    anchor: Option<String>,
+    tag_key: String,
+    tag_value: String,
+}
+
+#[derive(Debug, Deserialize, Row)]
+struct DataItemKey {
+    dataitem_id: String,
+    indexed_at: i64,
+}
+
+#[derive(Debug, Clone)]
+struct ClickhouseConfig {
+    url: String,
+    database: String,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+impl ClickhouseConfig {
+    fn load() -> Result<Self> {
+        let url = std::env::var("CLICKHOUSE_URL").context("CLICKHOUSE_URL env var not set")?;
+        let database = std::env::var("CLICKHOUSE_DATABASE").unwrap_or_default();
+        let user = std::env::var("CLICKHOUSE_USER").ok().filter(|v| !v.is_empty());
+        let password = std::env::var("CLICKHOUSE_PASSWORD").ok().filter(|v| !v.is_empty());
+        Ok(Self { url, database, user, password })
+    }
+}
+
+/// Tags indexed into the `dataitem_tags` ClickHouse table. Rows are handed to a background
+/// task that buffers them across dataitems and flushes every `max_rows` rows or `period`,
+/// whichever comes first. A dataitem counts as indexed once the flush holding its rows is
+/// committed.
+#[derive(Clone)]
+pub struct ClickhouseIndexer {
+    client: Client,
+    inserter: mpsc::Sender<InserterMessage>,
+}
+
+// resolved once the INSERT holding the batch is committed, or with the error that lost it
+type Committed = oneshot::Sender<Result<()>>;
+
+enum InserterMessage {
+    Batch { rows: Vec<TagRow>, committed: Committed },
+    // flush what is buffered and stop, answered once the final INSERT ended
+    Shutdown(oneshot::Sender<()>),
+}
+
+impl ClickhouseIndexer {
+    /// Build the ClickHouse client from the `CLICKHOUSE_*` env vars, set up the schema and
+    /// start the inserter task, once at startup.
+    pub async fn connect(max_rows: u64, period: Duration) -> Result<Self> {
+        let cfg = ClickhouseConfig::load()?;
+        let mut client = Client::default().with_url(cfg.url).with_database(cfg.database);
+        if let Some(user) = cfg.user {
+            client = client.with_user(user);
+        }
+        if let Some(password) = cfg.password {
+            client = client.with_password(password);
+        }
+
+        let schema_timer = METRICS.backend("clickhouse", "ensure_schema").start_timer();
+        ensure_schema(&client).await?;
+        schema_timer.observe_duration();
+
+        let inserter = client
+            .inserter::<TagRow>("dataitem_tags")?
+            .with_max_rows(max_rows)
+            .with_period(Some(period));
+        let (sender, pending) = mpsc::channel(PENDING_DATAITEMS);
+        tokio::spawn(run_inserter(inserter, pending, period));
+
+        Ok(Self { client, inserter: sender })
+    }
+}
+
+async fn run_inserter(
+    mut inserter: Inserter<TagRow>,
+    mut pending: mpsc::Receiver<InserterMessage>,
+    period: Duration,
+) {
+    // batches written into the open INSERT, acknowledged when it ends
+    let mut uncommitted = Vec::new();
+    // commit() only flushes once a limit is hit, tick so a quiet period still flushes
+    let mut tick = tokio::time::interval(period);
+    let stopped = loop {
+        tokio::select! {
+            message = pending.recv() => match message {
+                Some(InserterMessage::Batch { rows, committed }) => {
+                    write_batch(&mut inserter, &rows, committed, &mut uncommitted);
+                }
+                Some(InserterMessage::Shutdown(stopped)) => break Some(stopped),
+                None => break None,
+            },
+            _ = tick.tick() => {}
+        }
+
+        let timer = METRICS.backend("clickhouse", "insert").start_timer();
+        match inserter.commit().await {
+            // only time commits that actually flushed rows
+            Ok(flushed) if flushed.rows > 0 => {
+                timer.observe_duration();
+                acknowledge(&mut uncommitted, None);
+            }
+            Ok(_) => {
+                timer.stop_and_discard();
+            }
+            Err(e) => {
+                error!(error = ?e, "clickhouse insert failed");
+                acknowledge(&mut uncommitted, Some(&e));
+            }
+        }
+    };
+
+    // refuse new batches, the ones already queued go into the final INSERT
+    pending.close();
+    while let Some(message) = pending.recv().await {
+        if let InserterMessage::Batch { rows, committed } = message {
+            write_batch(&mut inserter, &rows, committed, &mut uncommitted);
+        }
+    }
+    match inserter.end().await {
+        Ok(_) => acknowledge(&mut uncommitted, None),
+        Err(e) => {
+            error!(error = ?e, "final clickhouse flush failed");
+            acknowledge(&mut uncommitted, Some(&e));
+        }
+    }
+
+    if let Some(stopped) = stopped {
+        let _ = stopped.send(());
+    }
+}
+
+fn write_batch(
+    inserter: &mut Inserter<TagRow>,
+    rows: &[TagRow],
+    committed: Committed,
+    uncommitted: &mut Vec<Committed>,
+) {
+    for row in rows {
+        if let Err(e) = inserter.write(row) {
+            error!(dataitem_id = row.dataitem_id, error = ?e, "writing tag row to clickhouse failed");
+            let _ = committed.send(Err(anyhow!("writing tag row to clickhouse failed: {e}")));
+            return;
+        }
+    }
+    uncommitted.push(committed);
+}
+
+// settle every batch of the INSERT that just ended
+fn acknowledge(uncommitted: &mut Vec<Committed>, failed: Option<&clickhouse::error::Error>) {
+    for committed in uncommitted.drain(..) {
+        let result = match failed {
+            Some(e) => Err(anyhow!("clickhouse insert failed: {e}")),
+            None => Ok(()),
+        };
+        let _ = committed.send(result);
+    }
+}
+
+async fn ensure_schema(client: &Client) -> Result<()> {
+    let mut needs_create = true;
+    let table_info: Option<ExistingTable> = client
+        .query(
+            "SELECT engine \
+             FROM system.tables \
+             WHERE database = currentDatabase() AND name = 'dataitem_tags'",
+        )
+        .fetch_optional()
+        .await
+        .context("failed to inspect existing dataitem_tags table")?;
+
+    if let Some(info) = table_info {
+        if info.engine == "ReplacingMergeTree" {
+            needs_create = false;
+        } else {
+            client
+                .query("DROP TABLE IF EXISTS dataitem_tags")
+                .execute()
+                .await
+                .context("failed to drop legacy dataitem_tags table")?;
+        }
+    }
+
+    if needs_create {
+        client.query(TABLE_DDL).execute().await?;
+    }
+
+    client
+        .query(
+            "ALTER TABLE dataitem_tags \
+             ADD COLUMN IF NOT EXISTS dataitem_size Nullable(UInt64) \
+             AFTER created_at",
+        )
+        .execute()
+        .await
+        .context("failed to ensure dataitem_size column")?;
+
+    client
+        .query(
+            "ALTER TABLE dataitem_tags \
+             ADD COLUMN IF NOT EXISTS owner Nullable(String) \
+             AFTER dataitem_size",
+        )
+        .execute()
+        .await
+        .context("failed to ensure owner column")?;
+
+    client
+        .query(
+            "ALTER TABLE dataitem_tags \
+             ADD COLUMN IF NOT EXISTS target Nullable(String) \
+             AFTER owner",
+        )
+        .execute()
+        .await
+        .context("failed to ensure target column")?;
+
+    client
+        .query(
+            "ALTER TABLE dataitem_tags \
+             ADD COLUMN IF NOT EXISTS token LowCardinality(String) DEFAULT '' \
+             AFTER target",
+        )
+        .execute()
+        .await
+        .context("failed to ensure token column")?;
+
+    for (column, after) in
+        [("owner_key", "token"), ("signature", "owner_key"), ("anchor", "signature")]
+    {
+        client
+            .query(&format!(
+                "ALTER TABLE dataitem_tags \
+                 ADD COLUMN IF NOT EXISTS {column} Nullable(String) \
+                 AFTER {after}"
+            ))
+            .execute()
+            .await
+            .with_context(|| format!("failed to ensure {column} column"))?;
+    }
+
+    // owners indexed before they were wallet addresses hold the 683 characters base64url
+    // modulus, rewritten to the base64url sha256 of it
+    let legacy: RowCount = client
+        .query("SELECT count() AS rows FROM dataitem_tags WHERE length(owner) = 683")
+        .fetch_one()
+        .await
+        .context("failed to count legacy arweave owners")?;
+    if legacy.rows > 0 {
+        client
+            .query(
+                "ALTER TABLE dataitem_tags UPDATE owner = \
+                 replaceAll(replaceAll(substring(base64Encode(SHA256(base64Decode(concat(\
+                 replaceAll(replaceAll(owner, '-', '+'), '_', '/'), '=')))), 1, 43), \
+                 '+', '-'), '/', '_') \
+                 WHERE length(owner) = 683",
+            )
+            .execute()
+            .await
+            .context("failed to migrate arweave owner addresses")?;
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl Indexer for ClickhouseIndexer {
+    async fn index_dataitem(&self, item: &IndexedDataItem<'_>) -> Result<()> {
+        let created_at = Utc::now().timestamp_millis();
+        let dataitem_size = u64::try_from(item.size).context("dataitem_size overflows u64")?;
+
+        let rows: Vec<TagRow> = tags_to_index(item.tags)
+            .into_iter()
+            .map(|(tag_key, tag_value)| TagRow {
+                dataitem_id: item.id.to_string(),
+                content_type: item.content_type.to_string(),
+                created_at,
+                dataitem_size: Some(dataitem_size),
+                owner: item.owner.map(str::to_string),
+                target: item.target.map(str::to_string),
+                token: item.token.to_string(),
+                owner_key: item.owner_key.map(str::to_string),
+                signature: item.signature.map(str::to_string),
+                anchor: item.anchor.map(str::to_string),
+                tag_key,
+                tag_value,
+            })
+            .collect();
+
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let (committed, acknowledged) = oneshot::channel();
+        self.inserter
+            .send(InserterMessage::Batch { rows, committed })
+            .await
+            .map_err(|_| anyhow!("clickhouse inserter task stopped"))?;
+        acknowledged.await.map_err(|_| anyhow!("clickhouse inserter task stopped"))?
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        let (stopped, flushed) = oneshot::channel();
+        self.inserter
+            .send(InserterMessage::Shutdown(stopped))
+            .await
+            .map_err(|_| anyhow!("clickhouse inserter task stopped"))?;
+        flushed.await.map_err(|_| anyhow!("clickhouse inserter task stopped"))
+    }
+
+    async fn query_dataitems(&self, query: &DataItemQuery) -> Result<Vec<IndexedEntry>> {
+        let _timer = METRICS.backend("clickhouse", "query_dataitems").start_timer();
+
+        let (sql, params) = dataitem_keys_sql(query, "toUnixTimestamp64Milli(created_at)");
+        let mut keys_query = self.client.query(&sql);
+        for param in params {
+            keys_query = keys_query.bind(param);
+        }
+        let keys: Vec<(String, i64)> = keys_query
+            .fetch_all::<DataItemKey>()
+            .await
+            .context("failed to query dataitem ids")?
+            .into_iter()
+            .map(|key| (key.dataitem_id, key.indexed_at))
+            .collect();
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let sql = format!(
+            "SELECT dataitem_id, content_type, created_at, dataitem_size, owner, target, token, \
+             owner_key, signature, anchor, tag_key, tag_value \
+             FROM dataitem_tags WHERE dataitem_id IN ({})",
+            id_placeholders(&keys)
+        );
+        let mut rows_query = self.client.query(&sql);
+        for (id, _) in &keys {
+            rows_query = rows_query.bind(id);
+        }
+        let rows =
+            rows_query.fetch_all::<TagRow>().await.context("failed to load dataitem tags")?;
+
+        Ok(collect_entries(
+            &keys,
+            rows.into_iter().map(|row| IndexedEntry {
+                id: row.dataitem_id,
+                content_type: row.content_type,
+                created_at: row.created_at,
+                size: row.dataitem_size,
+                owner: row.owner,
+                owner_key: row.owner_key,
+                signature: row.signature,
+                anchor: row.anchor,
+                target: row.target,
+                token: row.token,
+                tags: vec![(row.tag_key, row.tag_value)],
+            }),
+        ))
+    }
+}
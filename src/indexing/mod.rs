@@ -1,126 +1,266 @@
-use anyhow::{Context, Result};
-use chrono::Utc;
-use clickhouse::{Client, Row};
-use once_cell::sync::OnceCell;
-use serde::Deserialize;
-use std::collections::BTreeSet;
-
-const TABLE_DDL: &str = r#"
-CREATE TABLE IF NOT EXISTS dataitem_tags
-(
-    dataitem_id String,
-    content_type String,
-    created_at   DateTime64(3, 'UTC'),
-    dataitem_size Nullable(UInt64),
-    owner Nullable(String),
-    target Nullable(String),
-    tag_key      String,
-    tag_value    String
-)
-ENGINE = ReplacingMergeTree(created_at)
-ORDER BY (tag_key, tag_value, dataitem_id);
-"#;
-
-static CLIENT: OnceCell<Client> = OnceCell::new();
-
-#[derive(Debug, Deserialize, Row)]
-struct ExistingTable {
-    engine: String,
+pub mod clickhouse;
+pub mod sqlite;
+
+use crate::arbundles::token::Token;
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use serde::Serialize;
+use std::{collections::BTreeSet, fmt};
+
+/// Tag index written to once per stored dataitem. The upload and finalize paths only see
+/// this trait, the implementation is picked from `indexer` in the config at startup.
+#[async_trait]
+pub trait Indexer: Send + Sync {
+    async fn index_dataitem(&self, item: &IndexedDataItem<'_>) -> Result<()>;
+
+    /// Indexed dataitems matching `query`, ordered by index time then id.
+    async fn query_dataitems(&self, query: &DataItemQuery) -> Result<Vec<IndexedEntry>>;
+
+    /// Flush whatever is buffered before the process exits.
+    async fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// What gets indexed for one stored dataitem.
+pub struct IndexedDataItem<'a> {
+    pub id: &'a str,
+    pub content_type: &'a str,
+    pub tags: &'a [(String, String)],
+    pub size: usize,
+    // owner address
+    pub owner: Option<&'a str>,
+    // base64url owner public key, signature and anchor as served by the gateways
+    pub owner_key: Option<&'a str>,
+    pub signature: Option<&'a str>,
+    pub anchor: Option<&'a str>,
+    pub target: Option<&'a str>,
+    // the upload route token the item came in through
+    pub token: Token,
+}
+
+/// Queries against an index that is turned off.
+#[derive(Debug)]
+pub struct IndexDisabled;
+
+impl fmt::Display for IndexDisabled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dataitem indexing is disabled on this bundler")
+    }
+}
+
+impl std::error::Error for IndexDisabled {}
+
+/// Filters over the indexed dataitems. Empty lists don't filter, every tag filter has to
+/// match with any of its values.
+#[derive(Debug, Clone, Default)]
+pub struct DataItemQuery {
+    pub ids: Vec<String>,
+    pub owners: Vec<String>,
+    pub targets: Vec<String>,
+    pub tags: Vec<TagFilter>,
+    // index time bounds in ms, inclusive
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub after: Option<DataItemCursor>,
+    pub limit: usize,
+    // oldest first, newest first otherwise
+    pub ascending: bool,
 }
 
 #[derive(Debug, Clone)]
-struct ClickhouseConfig {
-    url: String,
-    database: String,
-    user: Option<String>,
-    password: Option<String>,
-}
-
-impl ClickhouseConfig {
-    fn load() -> Result<Self> {
-        let url = std::env::var("CLICKHOUSE_URL").context("CLICKHOUSE_URL env var not set")?;
-        let database = std::env::var("CLICKHOUSE_DATABASE").unwrap_or_default();
-        let user = std::env::var("CLICKHOUSE_USER").ok().filter(|v| !v.is_empty());
-        let password = std::env::var("CLICKHOUSE_PASSWORD").ok().filter(|v| !v.is_empty());
-        Ok(Self { url, database, user, password })
+pub struct TagFilter {
+    pub name: String,
+    // any value when empty
+    pub values: Vec<String>,
+}
+
+/// Position of an entry in the index order, opaque to clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataItemCursor {
+    pub created_at: i64,
+    pub id: String,
+}
+
+impl DataItemCursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!("{}:{}", self.created_at, self.id))
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid cursor {cursor}");
+        let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?)
+            .map_err(|_| invalid())?;
+        let (created_at, id) = decoded.split_once(':').ok_or_else(invalid)?;
+        Ok(Self { created_at: created_at.parse().map_err(|_| invalid())?, id: id.to_string() })
     }
 }
 
-fn client() -> Result<&'static Client> {
-    CLIENT.get_or_try_init(|| {
-        let cfg = ClickhouseConfig::load()?;
-        let mut builder = Client::default().with_url(cfg.url).with_database(cfg.database);
-        if let Some(user) = cfg.user {
-            builder = builder.with_user(user);
-        }
-        if let Some(password) = cfg.password {
-            builder = builder.with_password(password);
-        }
-        Ok(builder)
-    })
-}
-
-async fn ensure_schema() -> Result<()> {
-    let client = client()?;
-
-    let mut needs_create = true;
-    let table_info: Option<ExistingTable> = client
-        .query(
-            "SELECT engine \
-             FROM system.tables \
-             WHERE database = currentDatabase() AND name = 'dataitem_tags'",
-        )
-        .fetch_optional()
-        .await
-        .context("failed to inspect existing dataitem_tags table")?;
-
-    if let Some(info) = table_info {
-        if info.engine == "ReplacingMergeTree" {
-            needs_create = false;
-        } else {
-            client
-                .query("DROP TABLE IF EXISTS dataitem_tags")
-                .execute()
-                .await
-                .context("failed to drop legacy dataitem_tags table")?;
-        }
+/// One dataitem as recorded in the index.
+#[derive(Debug, Clone)]
+pub struct IndexedEntry {
+    pub id: String,
+    pub content_type: String,
+    // ms since the epoch
+    pub created_at: i64,
+    pub size: Option<u64>,
+    pub owner: Option<String>,
+    pub owner_key: Option<String>,
+    pub signature: Option<String>,
+    pub anchor: Option<String>,
+    pub target: Option<String>,
+    pub token: String,
+    pub tags: Vec<(String, String)>,
+}
+
+impl IndexedEntry {
+    pub fn cursor(&self) -> DataItemCursor {
+        DataItemCursor { created_at: self.created_at, id: self.id.clone() }
+    }
+}
+
+/// Indexing turned off, dataitems are only stored.
+pub struct NoopIndexer;
+
+#[async_trait]
+impl Indexer for NoopIndexer {
+    async fn index_dataitem(&self, _item: &IndexedDataItem<'_>) -> Result<()> {
+        Ok(())
     }
 
-    if needs_create {
-        client.query(TABLE_DDL).execute().await?;
+    async fn query_dataitems(&self, _query: &DataItemQuery) -> Result<Vec<IndexedEntry>> {
+        Err(IndexDisabled.into())
     }
+}
 
-    client
-        .query(
-            "ALTER TABLE dataitem_tags \
-             ADD COLUMN IF NOT EXISTS dataitem_size Nullable(UInt64) \
-             AFTER created_at",
-        )
-        .execute()
-        .await
-        .context("failed to ensure dataitem_size column")?;
-
-    client
-        .query(
-            "ALTER TABLE dataitem_tags \
-             ADD COLUMN IF NOT EXISTS owner Nullable(String) \
-             AFTER dataitem_size",
-        )
-        .execute()
-        .await
-        .context("failed to ensure owner column")?;
-
-    client
-        .query(
-            "ALTER TABLE dataitem_tags \
-             ADD COLUMN IF NOT EXISTS target Nullable(String) \
-             AFTER owner",
-        )
-        .execute()
-        .await
-        .context("failed to ensure target column")?;
-
-    Ok(())
+/// A bound value of a query built by [`dataitem_keys_sql`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub(crate) enum QueryParam {
+    Text(String),
+    Int(i64),
+}
+
+/// `(dataitem_id, indexed_at)` of the dataitems matching `query`, in page order, over the
+/// `dataitem_tags` rows both indexers share. `created_at_ms` is the column expression in
+/// ms for the backend.
+pub(crate) fn dataitem_keys_sql(
+    query: &DataItemQuery,
+    created_at_ms: &str,
+) -> (String, Vec<QueryParam>) {
+    let mut sql = format!(
+        "SELECT dataitem_id, max({created_at_ms}) AS indexed_at FROM dataitem_tags WHERE 1 = 1"
+    );
+    let mut params = Vec::new();
+    push_in(&mut sql, &mut params, "dataitem_id", &query.ids);
+    push_in(&mut sql, &mut params, "owner", &query.owners);
+    push_in(&mut sql, &mut params, "target", &query.targets);
+    for filter in &query.tags {
+        sql.push_str(
+            " AND dataitem_id IN (SELECT dataitem_id FROM dataitem_tags WHERE tag_key = ?",
+        );
+        params.push(QueryParam::Text(filter.name.clone()));
+        push_in(&mut sql, &mut params, "tag_value", &filter.values);
+        sql.push(')');
+    }
+
+    sql.push_str(" GROUP BY dataitem_id HAVING 1 = 1");
+    if let Some(from) = query.from {
+        sql.push_str(" AND indexed_at >= ?");
+        params.push(QueryParam::Int(from));
+    }
+    if let Some(to) = query.to {
+        sql.push_str(" AND indexed_at <= ?");
+        params.push(QueryParam::Int(to));
+    }
+    let (op, order) = if query.ascending { (">", "ASC") } else { ("<", "DESC") };
+    if let Some(after) = &query.after {
+        sql.push_str(&format!(
+            " AND (indexed_at {op} ? OR (indexed_at = ? AND dataitem_id {op} ?))"
+        ));
+        params.push(QueryParam::Int(after.created_at));
+        params.push(QueryParam::Int(after.created_at));
+        params.push(QueryParam::Text(after.id.clone()));
+    }
+    sql.push_str(&format!(" ORDER BY indexed_at {order}, dataitem_id {order} LIMIT ?"));
+    params.push(QueryParam::Int(query.limit as i64));
+
+    (sql, params)
+}
+
+fn push_in(sql: &mut String, params: &mut Vec<QueryParam>, column: &str, values: &[String]) {
+    if values.is_empty() {
+        return;
+    }
+    let placeholders = vec!["?"; values.len()].join(", ");
+    sql.push_str(&format!(" AND {column} IN ({placeholders})"));
+    params.extend(values.iter().cloned().map(QueryParam::Text));
+}
+
+/// `?` placeholders for one bound value per dataitem id.
+pub(crate) fn id_placeholders(ids: &[(String, i64)]) -> String {
+    vec!["?"; ids.len()].join(", ")
+}
+
+/// Fold per-tag rows into one entry per dataitem, in the order of `keys`. Rows carry a
+/// single tag each, replicas of a row (ClickHouse merges them lazily) are dropped.
+pub(crate) fn collect_entries(
+    keys: &[(String, i64)],
+    rows: impl IntoIterator<Item = IndexedEntry>,
+) -> Vec<IndexedEntry> {
+    let mut entries: Vec<IndexedEntry> = Vec::with_capacity(keys.len());
+    let mut by_id = std::collections::HashMap::with_capacity(keys.len());
+    for (id, indexed_at) in keys {
+        by_id.insert(id.as_str(), entries.len());
+        entries.push(IndexedEntry {
+            id: id.clone(),
+            content_type: String::new(),
+            created_at: *indexed_at,
+            size: None,
+            owner: None,
+            owner_key: None,
+            signature: None,
+            anchor: None,
+            target: None,
+            token: String::new(),
+            tags: Vec::new(),
+        });
+    }
+
+    for row in rows {
+        let Some(&position) = by_id.get(row.id.as_str()) else {
+            continue;
+        };
+        let entry = &mut entries[position];
+        // the latest row wins for the item level fields
+        if row.created_at >= entry.created_at || entry.content_type.is_empty() {
+            entry.content_type = row.content_type;
+            entry.size = row.size.or(entry.size);
+            entry.owner = row.owner.or(entry.owner.take());
+            entry.owner_key = row.owner_key.or(entry.owner_key.take());
+            entry.signature = row.signature.or(entry.signature.take());
+            entry.anchor = row.anchor.or(entry.anchor.take());
+            entry.target = row.target.or(entry.target.take());
+            entry.token = row.token;
+        }
+        for tag in row.tags {
+            if !entry.tags.contains(&tag) {
+                entry.tags.push(tag);
+            }
+        }
+    }
+    entries
+}
+
+/// The dataitem tags plus the ones every stored item carries, trimmed and deduplicated.
+pub(crate) fn tags_to_index(tags: &[(String, String)]) -> Vec<(String, String)> {
+    let mut base_tags: Vec<(String, String)> =
+        tags.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    base_tags.push(("Storage-Provider".to_string(), "Load-S3".to_string()));
+    base_tags.push(("Client".to_string(), "Loaded-Turbo-API".to_string()));
+    normalize_tags(&base_tags)
 }
 
 fn normalize_tags(tags: &[(String, String)]) -> Vec<(String, String)> {
@@ -143,49 +283,3 @@ fn normalize_tags(tags: &[(String, String)]) -> Vec<(String, String)> {
     }
     normalized
 }
-
-pub async fn index_dataitem(
-    dataitem_id: &str,
-    content_type: &str,
-    tags: &[(String, String)],
-    dataitem_size: usize,
-    owner: Option<String>,
-    target: Option<String>,
-) -> Result<()> {
-    ensure_schema().await?;
-    let client = client()?;
-    let created_at_sql = Utc::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string();
-    let mut base_tags: Vec<(String, String)> =
-        tags.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
-    base_tags.push(("Storage-Provider".to_string(), "Load-S3".to_string()));
-    base_tags.push(("Client".to_string(), "Loaded-Turbo-API".to_string()));
-    let normalized = normalize_tags(&base_tags);
-    let dataitem_size = u64::try_from(dataitem_size).context("dataitem_size overflows u64")?;
-
-    if normalized.is_empty() {
-        return Ok(());
-    }
-
-    for (tag_key, tag_value) in normalized.iter() {
-        client
-            .query(
-                "INSERT INTO dataitem_tags \
-                 (dataitem_id, content_type, created_at, dataitem_size, owner, target, tag_key, tag_value) \
-                 VALUES (?, ?, toDateTime64(?, 3, 'UTC'), ?, ?, ?, ?, ?)",
-            )
-            .bind(dataitem_id)
-            .bind(content_type)
-            .bind(&created_at_sql)
-            .bind(dataitem_size)
-            .bind(owner.clone())
-            .bind(target.clone())
-            .bind(tag_key)
-            .bind(tag_value)
-            .execute()
-            .await
-            .with_context(|| {
-                format!("failed to insert tag ({tag_key}, {tag_value}) for dataitem {dataitem_id}")
-            })?;
-    }
-    Ok(())
-}
@@ -0,0 +1,160 @@
+use crate::{
+    db::{add_column_if_missing, migrate_arweave_owner_addresses},
+    indexing::{
+        DataItemQuery, IndexedDataItem, IndexedEntry, Indexer, QueryParam, collect_entries,
+        dataitem_keys_sql, id_placeholders, tags_to_index,
+    },
+    metrics::METRICS,
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::{Row, SqlitePool};
+
+/// Tags indexed into a `dataitem_tags` table next to the upload tables, for small
+/// deployments that don't run ClickHouse. Same rows as the ClickHouse table.
+#[derive(Clone)]
+pub struct SqliteIndexer {
+    pool: SqlitePool,
+}
+
+impl SqliteIndexer {
+    pub async fn new(pool: SqlitePool) -> Result<Self> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS dataitem_tags (
+                dataitem_id TEXT NOT NULL,
+                content_type TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                dataitem_size INTEGER,
+                owner TEXT,
+                target TEXT,
+                token TEXT,
+                owner_key TEXT,
+                signature TEXT,
+                anchor TEXT,
+                tag_key TEXT NOT NULL,
+                tag_value TEXT NOT NULL,
+                PRIMARY KEY (tag_key, tag_value, dataitem_id)
+            )
+        "#,
+        )
+        .execute(&pool)
+        .await
+        .context("failed to create dataitem_tags table")?;
+
+        // tables created before the token column existed
+        add_column_if_missing(&pool, "dataitem_tags", "token", "TEXT").await?;
+
+        // tables created before the owner key, signature and anchor were indexed
+        for column in ["owner_key", "signature", "anchor"] {
+            add_column_if_missing(&pool, "dataitem_tags", column, "TEXT").await?;
+        }
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_dataitem_tags_dataitem_id ON dataitem_tags (dataitem_id)",
+        )
+        .execute(&pool)
+        .await
+        .context("failed to create dataitem_tags index")?;
+
+        migrate_arweave_owner_addresses(&pool, "dataitem_tags", "owner")
+            .await
+            .context("failed to migrate arweave owner addresses")?;
+
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl Indexer for SqliteIndexer {
+    async fn index_dataitem(&self, item: &IndexedDataItem<'_>) -> Result<()> {
+        let normalized = tags_to_index(item.tags);
+        if normalized.is_empty() {
+            return Ok(());
+        }
+
+        let _timer = METRICS.backend("sqlite", "index_dataitem").start_timer();
+        let created_at = Utc::now().timestamp_millis();
+        let dataitem_size = i64::try_from(item.size).context("dataitem_size overflows i64")?;
+
+        // all tags of an item land together or not at all
+        let mut tx = self.pool.begin().await?;
+        for (tag_key, tag_value) in normalized.iter() {
+            sqlx::query(
+                "INSERT OR REPLACE INTO dataitem_tags \
+                 (dataitem_id, content_type, created_at, dataitem_size, owner, target, token, \
+                 owner_key, signature, anchor, tag_key, tag_value) \
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(item.id)
+            .bind(item.content_type)
+            .bind(created_at)
+            .bind(dataitem_size)
+            .bind(item.owner)
+            .bind(item.target)
+            .bind(item.token.as_str())
+            .bind(item.owner_key)
+            .bind(item.signature)
+            .bind(item.anchor)
+            .bind(tag_key)
+            .bind(tag_value)
+            .execute(&mut *tx)
+            .await
+            .with_context(|| {
+                format!("failed to insert tag ({tag_key}, {tag_value}) for dataitem {}", item.id)
+            })?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+    async fn query_dataitems(&self, query: &DataItemQuery) -> Result<Vec<IndexedEntry>> {
+        let _timer = METRICS.backend("sqlite", "query_dataitems").start_timer();
+
+        let (sql, params) = dataitem_keys_sql(query, "created_at");
+        let mut keys_query = sqlx::query_as::<_, (String, i64)>(&sql);
+        for param in params {
+            keys_query = match param {
+                QueryParam::Text(value) => keys_query.bind(value),
+                QueryParam::Int(value) => keys_query.bind(value),
+            };
+        }
+        let keys =
+            keys_query.fetch_all(&self.pool).await.context("failed to query dataitem ids")?;
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let sql = format!(
+            "SELECT dataitem_id, content_type, created_at, dataitem_size, owner, target, token, \
+             owner_key, signature, anchor, tag_key, tag_value \
+             FROM dataitem_tags WHERE dataitem_id IN ({})",
+            id_placeholders(&keys)
+        );
+        let mut rows_query = sqlx::query(&sql);
+        for (id, _) in &keys {
+            rows_query = rows_query.bind(id);
+        }
+        let rows =
+            rows_query.fetch_all(&self.pool).await.context("failed to load dataitem tags")?;
+
+        Ok(collect_entries(
+            &keys,
+            rows.into_iter().map(|row| IndexedEntry {
+                id: row.get("dataitem_id"),
+                content_type: row.get("content_type"),
+                created_at: row.get("created_at"),
+                size: row.get::<Option<i64>, _>("dataitem_size").map(|size| size.max(0) as u64),
+                owner: row.get("owner"),
+                owner_key: row.get("owner_key"),
+                signature: row.get("signature"),
+                anchor: row.get("anchor"),
+                target: row.get("target"),
+                token: row.get::<Option<String>, _>("token").unwrap_or_default(),
+                tags: vec![(row.get("tag_key"), row.get("tag_value"))],
+            }),
+        ))
+    }
+}
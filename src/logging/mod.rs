@@ -0,0 +1,36 @@
+use crate::config::LogFormat;
+use axum::{extract::MatchedPath, http::Request};
+use tracing::{Span, info_span};
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_FILTER: &str = "info";
+
+/// Install the global subscriber. Levels come from `RUST_LOG` (e.g. `info,sqlx=warn`),
+/// defaulting to `info`.
+pub fn init(format: LogFormat) {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match format {
+        LogFormat::Pretty => builder.pretty().init(),
+        // every event carries the fields of all the spans it happened in
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).init(),
+    }
+}
+
+/// Root span of a request. Handlers open their own span below it carrying the token,
+/// upload_id and dataitem_id.
+pub fn request_span<B>(request: &Request<B>) -> Span {
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| request.uri().path(), MatchedPath::as_str);
+
+    info_span!("request", request_id, method = %request.method(), route)
+}
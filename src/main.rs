@@ -1,30 +1,58 @@
 use crate::{
     api::{
+        admin::{
+            get_access_policy_handler, reload_access_policy_handler, remove_owner_quota_handler,
+            remove_owner_rule_handler, require_admin_token, set_access_mode_handler,
+            set_owner_quota_handler, set_owner_rule_handler,
+        },
+        graphql::{graphiql_handler, graphql_handler},
         handlers::{
             handle_bundler_metrics, handle_dataitem_status, handle_health, handle_info,
-            handle_load_info, handle_tx_offsets, upload_tx_handler,
+            handle_load_info, handle_owner_usage, handle_tx_offsets, upload_tx_handler,
         },
         multipart_uploads::{
-            create_multipart_upload_handler, finalize_multipart_upload_handler,
-            get_multipart_upload_handler, get_multipart_upload_status_handler, post_chunk_handler,
+            cancel_multipart_upload_handler, create_multipart_upload_handler,
+            finalize_multipart_upload_handler, get_multipart_upload_handler,
+            get_multipart_upload_status_handler, post_chunk_handler,
         },
+        receipts::{handle_dataitem_receipt, handle_verify_receipt},
+        retrieval::{handle_dataitem_data, handle_dataitem_metadata, handle_dataitem_raw},
+        search::{handle_owner_dataitems, handle_search},
     },
-    db::init_db,
-    utils::{OBJECT_SIZE_LIMIT, SERVER_PORT},
+    config::Config,
+    logging::request_span,
+    metrics::track_in_flight,
+    state::AppState,
 };
 use axum::{
     Router,
     extract::DefaultBodyLimit,
-    routing::{get, post},
+    middleware,
+    routing::{delete, get, post, put},
 };
 use dotenvy::dotenv;
 use rustls::crypto::ring::default_provider;
-use tower_http::{cors::CorsLayer, limit::RequestBodyLimitLayer};
+use tower_http::{
+    cors::CorsLayer,
+    limit::RequestBodyLimitLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::{DefaultOnResponse, TraceLayer},
+};
+use tracing::{Level, error, info};
+mod access;
 mod api;
 mod arbundles;
+mod config;
 mod db;
+mod finalizer;
+mod graphql;
 mod indexing;
-mod s3;
+mod logging;
+mod metrics;
+mod quota;
+mod state;
+mod storage;
+mod sweeper;
 mod utils;
 
 #[tokio::main]
@@ -34,37 +62,115 @@ async fn main() {
     // Load environment variables from a .env file if present
     dotenv().ok();
 
-    let db_pool = init_db().await.expect("Failed to initialize database");
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e:#}");
+            std::process::exit(1);
+        }
+    };
+
+    logging::init(config.log_format);
+
+    let state = match AppState::init(config).await {
+        Ok(state) => state,
+        Err(e) => {
+            error!("Failed to start: {e:#}");
+            std::process::exit(1);
+        }
+    };
+    let object_size_limit = state.config.object_size_limit;
+    let indexer = state.indexer.clone();
+    let port = state.config.server_port;
 
     let cors = CorsLayer::new()
         .allow_origin(tower_http::cors::Any)
         .allow_methods(tower_http::cors::Any)
         .allow_headers(tower_http::cors::Any);
 
-    let router = Router::new()
+    let mut router = Router::new()
         .route("/", get(handle_info))
         .route("/info", get(handle_info))
         .route("/internal", get(handle_load_info))
         .route("/bundler_metrics", get(handle_bundler_metrics))
         .route("/health", get(handle_health))
         .route("/v1/tx/{dataitem_id}/status", get(handle_dataitem_status))
-        .route("/v1/tx/{token}", post(upload_tx_handler))
+        // same segment: a token for uploads, a dataitem id for metadata lookups
+        .route("/v1/tx/{token}", post(upload_tx_handler).get(handle_dataitem_metadata))
         .route("/v1/tx/{dataitem_id}/offsets", get(handle_tx_offsets))
+        .route("/v1/tx/{dataitem_id}/data", get(handle_dataitem_data))
+        .route("/v1/tx/{dataitem_id}/raw", get(handle_dataitem_raw))
+        .route("/v1/tx/{dataitem_id}/receipt", get(handle_dataitem_receipt))
+        .route("/v1/owners/{owner}/usage", get(handle_owner_usage))
+        .route("/v1/owners/{owner}/dataitems", get(handle_owner_dataitems))
+        .route("/v1/search", get(handle_search))
+        .route("/v1/receipts/verify", post(handle_verify_receipt))
+        .route("/graphql", get(graphiql_handler).post(graphql_handler))
         // multipart upload
         .route("/v1/chunks/{token}/-1/-1", get(create_multipart_upload_handler))
         .route("/v1/chunks/{token}/{upload_id}/-1", get(get_multipart_upload_handler))
+        .route("/v1/chunks/{token}/{upload_id}", delete(cancel_multipart_upload_handler))
         .route("/v1/chunks/{token}/{upload_id}/status", get(get_multipart_upload_status_handler))
         .route("/v1/chunks/{token}/{upload_id}/{offset}", post(post_chunk_handler))
-        .route("/v1/chunks/{token}/{upload_id}/finalize", post(finalize_multipart_upload_handler))
-        .layer(DefaultBodyLimit::max(OBJECT_SIZE_LIMIT))
-        .layer(RequestBodyLimitLayer::new(OBJECT_SIZE_LIMIT))
-        .layer(cors)
-        .with_state(db_pool);
+        .route("/v1/chunks/{token}/{upload_id}/finalize", post(finalize_multipart_upload_handler));
 
-    // Use SERVER_PORT from env if set, otherwise default to the constant
-    let port = std::env::var("SERVER_PORT").unwrap_or_else(|_| SERVER_PORT.to_string());
+    if state.config.admin_token.is_some() {
+        let admin = Router::new()
+            .route("/admin/access", get(get_access_policy_handler))
+            .route("/admin/access/mode", put(set_access_mode_handler))
+            .route(
+                "/admin/access/owners/{owner}",
+                put(set_owner_rule_handler).delete(remove_owner_rule_handler),
+            )
+            .route("/admin/access/reload", post(reload_access_policy_handler))
+            .route(
+                "/admin/quotas/{owner}",
+                put(set_owner_quota_handler).delete(remove_owner_quota_handler),
+            )
+            .route_layer(middleware::from_fn_with_state(state.clone(), require_admin_token));
+        router = router.merge(admin);
+    }
+
+    let router = router
+        .layer(middleware::from_fn(track_in_flight))
+        .layer(DefaultBodyLimit::max(object_size_limit))
+        .layer(RequestBodyLimitLayer::new(object_size_limit))
+        .layer(cors)
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(request_span)
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        )
+        // outermost: the id is assigned before the span is made and echoed in the response
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .with_state(state);
 
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{port}")).await.unwrap();
-    println!("Server running on PORT: {port}");
-    axum::serve(listener, router).await.unwrap();
+    info!("Server running on PORT: {port}");
+    axum::serve(listener, router).with_graceful_shutdown(shutdown_signal()).await.unwrap();
+
+    // tag rows still buffered for the index would be lost otherwise
+    if let Err(e) = indexer.shutdown().await {
+        error!("Failed to flush the tag index: {e:#}");
+    }
+    info!("Server stopped");
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to listen for ctrl-c");
+    };
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    info!("Shutting down");
 }
@@ -0,0 +1,143 @@
+use crate::arbundles::token::Token;
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use prometheus::{
+    Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder, exponential_buckets,
+};
+use std::sync::LazyLock;
+
+/// Process-wide metrics, rendered in the Prometheus text format on `/bundler_metrics`.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// dataitem uploads by `endpoint` (tx, chunks), route `token`, `signature_type` and
+    /// `outcome`
+    pub uploads_total: IntCounterVec,
+    /// dataitem bytes stored, same labels as `uploads_total`
+    pub upload_bytes_total: IntCounterVec,
+    /// multipart sessions by `event` (created, finalized, failed, expired, canceled)
+    pub multipart_sessions_total: IntCounterVec,
+    /// stored part bytes dropped with expired multipart sessions
+    pub multipart_expired_bytes_total: IntCounter,
+    /// latency of calls to the storage, database and index backends,
+    /// by `backend` (s3, fs, sqlite, clickhouse) and `operation`
+    pub backend_duration_seconds: HistogramVec,
+    pub receipt_signing_seconds: Histogram,
+    /// requests being served, by matched `route`
+    pub requests_in_flight: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let uploads_total = IntCounterVec::new(
+            Opts::new("uploads_total", "Dataitem uploads"),
+            &["endpoint", "token", "signature_type", "outcome"],
+        )
+        .unwrap();
+        let upload_bytes_total = IntCounterVec::new(
+            Opts::new("upload_bytes_total", "Dataitem bytes uploaded"),
+            &["endpoint", "token", "signature_type", "outcome"],
+        )
+        .unwrap();
+        let multipart_sessions_total = IntCounterVec::new(
+            Opts::new("multipart_sessions_total", "Multipart upload session events"),
+            &["event"],
+        )
+        .unwrap();
+        let multipart_expired_bytes_total = IntCounter::new(
+            "multipart_expired_bytes_total",
+            "Stored part bytes freed by aborting abandoned multipart sessions",
+        )
+        .unwrap();
+        // 1ms .. ~65s
+        let backend_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("backend_duration_seconds", "Backend call latency in seconds")
+                .buckets(exponential_buckets(0.001, 2.0, 17).unwrap()),
+            &["backend", "operation"],
+        )
+        .unwrap();
+        let receipt_signing_seconds = Histogram::with_opts(
+            HistogramOpts::new("receipt_signing_seconds", "Receipt signing time in seconds")
+                .buckets(exponential_buckets(0.0005, 2.0, 12).unwrap()),
+        )
+        .unwrap();
+        let requests_in_flight = IntGaugeVec::new(
+            Opts::new("requests_in_flight", "Requests currently being served"),
+            &["route"],
+        )
+        .unwrap();
+
+        // names are unique and static, registering can't fail
+        registry.register(Box::new(uploads_total.clone())).unwrap();
+        registry.register(Box::new(upload_bytes_total.clone())).unwrap();
+        registry.register(Box::new(multipart_sessions_total.clone())).unwrap();
+        registry.register(Box::new(multipart_expired_bytes_total.clone())).unwrap();
+        registry.register(Box::new(backend_duration_seconds.clone())).unwrap();
+        registry.register(Box::new(receipt_signing_seconds.clone())).unwrap();
+        registry.register(Box::new(requests_in_flight.clone())).unwrap();
+
+        Self {
+            registry,
+            uploads_total,
+            upload_bytes_total,
+            multipart_sessions_total,
+            multipart_expired_bytes_total,
+            backend_duration_seconds,
+            receipt_signing_seconds,
+            requests_in_flight,
+        }
+    }
+
+    pub fn record_upload(
+        &self,
+        endpoint: &str,
+        token: Token,
+        signature_type: &str,
+        outcome: &str,
+        bytes: usize,
+    ) {
+        let labels = [endpoint, token.as_str(), signature_type, outcome];
+        self.uploads_total.with_label_values(&labels).inc();
+        self.upload_bytes_total.with_label_values(&labels).inc_by(bytes as u64);
+    }
+
+    /// Histogram for one backend call, use with `start_timer()`.
+    pub fn backend(&self, backend: &str, operation: &str) -> Histogram {
+        self.backend_duration_seconds.with_label_values(&[backend, operation])
+    }
+
+    pub fn render(&self) -> String {
+        TextEncoder::new().encode_to_string(&self.registry.gather()).unwrap_or_default()
+    }
+}
+
+// decrements on drop, so cancelled or panicking requests are not counted forever
+struct InFlight(IntGauge);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Middleware tracking `requests_in_flight` per matched route.
+pub async fn track_in_flight(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let gauge = METRICS.requests_in_flight.with_label_values(&[route.as_str()]);
+    gauge.inc();
+    let _in_flight = InFlight(gauge);
+
+    next.run(request).await
+}
@@ -0,0 +1,385 @@
+use crate::{db::migrate_arweave_owner_addresses, metrics::METRICS};
+use anyhow::Error;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool};
+use std::fmt;
+
+/// Returned (wrapped in an [`anyhow::Error`]) when storing a DataItem would take its owner
+/// past the free upload limit or one of its quotas. Answered with `402`.
+#[derive(Debug)]
+pub struct InsufficientBalance(pub String);
+
+impl fmt::Display for InsufficientBalance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Insufficient balance: {}", self.0)
+    }
+}
+
+impl std::error::Error for InsufficientBalance {}
+
+/// Returned (wrapped in an [`anyhow::Error`]) when a DataItem alone is larger than its
+/// owner's quota, so it can never be accepted. Answered with `413`.
+#[derive(Debug)]
+pub struct DataItemTooLarge(pub String);
+
+impl fmt::Display for DataItemTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Data item is too large: {}", self.0)
+    }
+}
+
+impl std::error::Error for DataItemTooLarge {}
+
+/// Byte limits of one owner, `None` meaning unlimited.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OwnerQuota {
+    pub daily_bytes: Option<u64>,
+    pub total_bytes: Option<u64>,
+}
+
+impl OwnerQuota {
+    fn is_set(&self) -> bool {
+        self.daily_bytes.is_some() || self.total_bytes.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OwnerUsage {
+    pub owner: String,
+    // owners with their own or a default quota are not held to the free upload limit
+    pub has_quota: bool,
+    // unset when the free upload limit is not enforced
+    pub free_upload_limit_bytes: Option<u64>,
+    pub daily_used_bytes: u64,
+    pub daily_limit_bytes: Option<u64>,
+    pub total_used_bytes: u64,
+    pub total_limit_bytes: Option<u64>,
+}
+
+/// Per-owner byte quotas and the usage ledger they are checked against, both in SQLite.
+/// Owners without a quota of their own fall back to the default quota, and only when that
+/// is unset too are they held to the free upload limit, if one is enforced. Days are UTC.
+pub struct Quotas {
+    pool: SqlitePool,
+    free_upload_limit_bytes: Option<u64>,
+    default_quota: OwnerQuota,
+}
+
+impl Quotas {
+    pub async fn new(
+        pool: SqlitePool,
+        free_upload_limit_bytes: Option<u64>,
+        default_quota: OwnerQuota,
+    ) -> Result<Self, Error> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS usage_ledger (
+                dataitem_id TEXT PRIMARY KEY,
+                owner_address TEXT NOT NULL,
+                bytes INTEGER NOT NULL,
+                recorded_at INTEGER NOT NULL
+            )
+        "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_usage_ledger_owner \
+             ON usage_ledger (owner_address, recorded_at)",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS owner_quotas (
+                owner_address TEXT PRIMARY KEY,
+                daily_bytes INTEGER,
+                total_bytes INTEGER,
+                updated_at INTEGER NOT NULL
+            )
+        "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        migrate_arweave_owner_addresses(&pool, "usage_ledger", "owner_address").await?;
+        migrate_arweave_owner_addresses(&pool, "owner_quotas", "owner_address").await?;
+
+        Ok(Self { pool, free_upload_limit_bytes, default_quota })
+    }
+
+    async fn owner_quota(&self, owner: &str) -> Result<Option<OwnerQuota>, Error> {
+        let _timer = METRICS.backend("sqlite", "get_owner_quota").start_timer();
+        let row = sqlx::query(
+            "SELECT daily_bytes, total_bytes FROM owner_quotas WHERE owner_address = ?",
+        )
+        .bind(owner)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| OwnerQuota {
+            daily_bytes: row.get::<Option<i64>, _>("daily_bytes").map(|b| b.max(0) as u64),
+            total_bytes: row.get::<Option<i64>, _>("total_bytes").map(|b| b.max(0) as u64),
+        }))
+    }
+
+    // (today, all time)
+    async fn used_bytes(&self, owner: &str) -> Result<(u64, u64), Error> {
+        let _timer = METRICS.backend("sqlite", "get_owner_usage").start_timer();
+        let day_start = Utc::now().date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc();
+        let row = sqlx::query(
+            "SELECT \
+                 COALESCE(SUM(CASE WHEN recorded_at >= ? THEN bytes ELSE 0 END), 0) AS daily, \
+                 COALESCE(SUM(bytes), 0) AS total \
+             FROM usage_ledger WHERE owner_address = ?",
+        )
+        .bind(day_start.timestamp())
+        .bind(owner)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((row.get::<i64, _>("daily").max(0) as u64, row.get::<i64, _>("total").max(0) as u64))
+    }
+
+    pub async fn usage(&self, owner: &str) -> Result<OwnerUsage, Error> {
+        let quota = self.owner_quota(owner).await?;
+        let (daily_used, total_used) = self.used_bytes(owner).await?;
+        let limits = quota.unwrap_or(self.default_quota);
+
+        Ok(OwnerUsage {
+            owner: owner.to_string(),
+            has_quota: quota.is_some() || self.default_quota.is_set(),
+            free_upload_limit_bytes: self.free_upload_limit_bytes,
+            daily_used_bytes: daily_used,
+            daily_limit_bytes: limits.daily_bytes,
+            total_used_bytes: total_used,
+            total_limit_bytes: limits.total_bytes,
+        })
+    }
+
+    async fn is_recorded(&self, dataitem_id: &str) -> Result<bool, Error> {
+        let _timer = METRICS.backend("sqlite", "get_recorded_usage").start_timer();
+        let row = sqlx::query("SELECT 1 FROM usage_ledger WHERE dataitem_id = ?")
+            .bind(dataitem_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    /// Fails with [`InsufficientBalance`] or [`DataItemTooLarge`] unless `owner` may store
+    /// `size` more bytes. A `dataitem_id` already recorded passes, storing it again costs
+    /// nothing. Concurrent uploads of one owner can overshoot by the items in flight.
+    pub async fn check(
+        &self,
+        owner: &str,
+        dataitem_id: Option<&str>,
+        size: u64,
+    ) -> Result<(), Error> {
+        let recorded = match dataitem_id {
+            Some(dataitem_id) => self.is_recorded(dataitem_id).await?,
+            None => false,
+        };
+        if recorded {
+            return Ok(());
+        }
+        let usage = self.usage(owner).await?;
+
+        let over_free_limit =
+            usage.free_upload_limit_bytes.filter(|&limit| !usage.has_quota && size > limit);
+        if let Some(free_limit) = over_free_limit {
+            return Err(InsufficientBalance(format!(
+                "{size} bytes is over the free upload limit of {free_limit} bytes"
+            ))
+            .into());
+        }
+
+        for (window, used, limit) in [
+            ("daily", usage.daily_used_bytes, usage.daily_limit_bytes),
+            ("total", usage.total_used_bytes, usage.total_limit_bytes),
+        ] {
+            let Some(limit) = limit else {
+                continue;
+            };
+            if size > limit {
+                return Err(DataItemTooLarge(format!(
+                    "{size} bytes is over the {window} quota of {limit} bytes"
+                ))
+                .into());
+            }
+            if used.saturating_add(size) > limit {
+                return Err(InsufficientBalance(format!(
+                    "{window} quota of {limit} bytes has {} bytes left",
+                    limit.saturating_sub(used)
+                ))
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add a stored DataItem to its owner's usage. Storing the same item again is not counted.
+    pub async fn record(&self, owner: &str, dataitem_id: &str, size: u64) -> Result<(), Error> {
+        let _timer = METRICS.backend("sqlite", "record_usage").start_timer();
+        sqlx::query(
+            "INSERT OR IGNORE INTO usage_ledger (dataitem_id, owner_address, bytes, recorded_at) \
+             VALUES (?, ?, ?, ?)",
+        )
+        .bind(dataitem_id)
+        .bind(owner)
+        .bind(i64::try_from(size)?)
+        .bind(Utc::now().timestamp())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_quota(&self, owner: &str, quota: OwnerQuota) -> Result<(), Error> {
+        let _timer = METRICS.backend("sqlite", "set_owner_quota").start_timer();
+        sqlx::query(
+            "INSERT OR REPLACE INTO owner_quotas (owner_address, daily_bytes, total_bytes, updated_at) \
+             VALUES (?, ?, ?, ?)",
+        )
+        .bind(owner)
+        .bind(quota.daily_bytes.map(i64::try_from).transpose()?)
+        .bind(quota.total_bytes.map(i64::try_from).transpose()?)
+        .bind(Utc::now().timestamp())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_quota(&self, owner: &str) -> Result<(), Error> {
+        let _timer = METRICS.backend("sqlite", "remove_owner_quota").start_timer();
+        sqlx::query("DELETE FROM owner_quotas WHERE owner_address = ?")
+            .bind(owner)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    const OWNER: &str = "owner";
+
+    async fn quotas(free_limit: Option<u64>, default_quota: OwnerQuota) -> Quotas {
+        Quotas::new(test_pool().await, free_limit, default_quota).await.unwrap()
+    }
+
+    fn refusal(checked: Result<(), Error>) -> &'static str {
+        match checked {
+            Ok(()) => "ok",
+            Err(e) if e.downcast_ref::<InsufficientBalance>().is_some() => "insufficient balance",
+            Err(e) if e.downcast_ref::<DataItemTooLarge>().is_some() => "too large",
+            Err(e) => panic!("unexpected error {e}"),
+        }
+    }
+
+    // move everything recorded so far to yesterday
+    async fn age_usage(quotas: &Quotas) {
+        sqlx::query("UPDATE usage_ledger SET recorded_at = recorded_at - 86400")
+            .execute(&quotas.pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn free_limit() {
+        let enforced = quotas(Some(100), OwnerQuota::default()).await;
+        assert_eq!(refusal(enforced.check(OWNER, None, 100).await), "ok");
+        assert_eq!(refusal(enforced.check(OWNER, None, 101).await), "insufficient balance");
+        // per item, not per owner
+        enforced.record(OWNER, "a", 100).await.unwrap();
+        assert_eq!(refusal(enforced.check(OWNER, None, 100).await), "ok");
+
+        let advertised = quotas(None, OwnerQuota::default()).await;
+        assert_eq!(refusal(advertised.check(OWNER, None, 1 << 40).await), "ok");
+
+        // an owner's own quota lifts it
+        let quota = OwnerQuota { daily_bytes: Some(1000), total_bytes: None };
+        enforced.set_quota(OWNER, quota).await.unwrap();
+        assert_eq!(refusal(enforced.check(OWNER, None, 500).await), "ok");
+    }
+
+    #[tokio::test]
+    async fn daily_quota() {
+        let quotas = quotas(Some(100), OwnerQuota::default()).await;
+        let quota = OwnerQuota { daily_bytes: Some(1000), total_bytes: None };
+        quotas.set_quota(OWNER, quota).await.unwrap();
+
+        quotas.record(OWNER, "a", 600).await.unwrap();
+        assert_eq!(refusal(quotas.check(OWNER, None, 400).await), "ok");
+        assert_eq!(refusal(quotas.check(OWNER, None, 401).await), "insufficient balance");
+        assert_eq!(refusal(quotas.check(OWNER, None, 1001).await), "too large");
+        // other owners have nothing recorded
+        assert_eq!(refusal(quotas.check("other", None, 100).await), "ok");
+
+        // yesterday's uploads don't count
+        age_usage(&quotas).await;
+        assert_eq!(refusal(quotas.check(OWNER, None, 1000).await), "ok");
+    }
+
+    #[tokio::test]
+    async fn total_quota() {
+        let quotas = quotas(Some(100), OwnerQuota::default()).await;
+        let quota = OwnerQuota { daily_bytes: None, total_bytes: Some(1000) };
+        quotas.set_quota(OWNER, quota).await.unwrap();
+
+        quotas.record(OWNER, "a", 600).await.unwrap();
+        age_usage(&quotas).await;
+        assert_eq!(refusal(quotas.check(OWNER, None, 400).await), "ok");
+        assert_eq!(refusal(quotas.check(OWNER, None, 401).await), "insufficient balance");
+        assert_eq!(refusal(quotas.check(OWNER, None, 1001).await), "too large");
+
+        let usage = quotas.usage(OWNER).await.unwrap();
+        assert_eq!((usage.daily_used_bytes, usage.total_used_bytes), (0, 600));
+    }
+
+    #[tokio::test]
+    async fn duplicate_dataitems() {
+        let quotas = quotas(Some(100), OwnerQuota::default()).await;
+        let quota = OwnerQuota { daily_bytes: Some(1000), total_bytes: None };
+        quotas.set_quota(OWNER, quota).await.unwrap();
+        quotas.record(OWNER, "a", 600).await.unwrap();
+
+        // storing "a" again costs nothing, so the quota left doesn't matter
+        assert_eq!(refusal(quotas.check(OWNER, Some("a"), 600).await), "ok");
+        assert_eq!(refusal(quotas.check(OWNER, Some("b"), 600).await), "insufficient balance");
+
+        quotas.record(OWNER, "a", 600).await.unwrap();
+        assert_eq!(quotas.usage(OWNER).await.unwrap().daily_used_bytes, 600);
+    }
+
+    #[tokio::test]
+    async fn default_quota() {
+        let default_quota = OwnerQuota { daily_bytes: Some(1000), total_bytes: None };
+        let quotas = quotas(Some(100), default_quota).await;
+
+        // applies to owners without their own and lifts the free limit
+        assert_eq!(refusal(quotas.check(OWNER, None, 1000).await), "ok");
+        assert_eq!(refusal(quotas.check(OWNER, None, 1001).await), "too large");
+        let usage = quotas.usage(OWNER).await.unwrap();
+        assert!(usage.has_quota);
+        assert_eq!(usage.daily_limit_bytes, Some(1000));
+
+        // an owner's own quota replaces it
+        let quota = OwnerQuota { daily_bytes: None, total_bytes: Some(5000) };
+        quotas.set_quota(OWNER, quota).await.unwrap();
+        assert_eq!(refusal(quotas.check(OWNER, None, 5000).await), "ok");
+        quotas.remove_quota(OWNER).await.unwrap();
+        assert_eq!(refusal(quotas.check(OWNER, None, 5000).await), "too large");
+    }
+}
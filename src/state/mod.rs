@@ -0,0 +1,238 @@
+use crate::{
+    access::AccessPolicy,
+    arbundles::ReceiptSigner,
+    config::{Config, IndexerKind, StorageKind},
+    db::init_db,
+    finalizer::{self, FinalizeQueue},
+    graphql::{GatewaySchema, build_schema},
+    indexing::{Indexer, NoopIndexer, clickhouse::ClickhouseIndexer, sqlite::SqliteIndexer},
+    quota::{OwnerQuota, Quotas},
+    storage::{StorageBackend, fs::FsStorage, s3::S3Storage},
+    sweeper,
+};
+use anyhow::{Context, Error};
+use axum::extract::FromRef;
+use sqlx::SqlitePool;
+use std::{sync::Arc, time::Duration};
+
+/// Shared state handed to every axum handler, built once at startup.
+#[derive(Clone)]
+pub struct AppState {
+    pub db_pool: SqlitePool,
+    pub storage: Arc<dyn StorageBackend>,
+    pub indexer: Arc<dyn Indexer>,
+    pub access: Arc<AccessPolicy>,
+    pub quotas: Arc<Quotas>,
+    pub receipt_signer: Arc<ReceiptSigner>,
+    pub graphql: GatewaySchema,
+    pub finalize: FinalizeQueue,
+    pub config: Arc<Config>,
+}
+
+impl AppState {
+    pub async fn init(config: Config) -> Result<Self, Error> {
+        let receipt_signer = Arc::new(ReceiptSigner::from_env()?);
+        let db_pool = init_db().await.context("failed to initialize database")?;
+        let storage: Arc<dyn StorageBackend> = match config.storage_backend {
+            StorageKind::S3 => {
+                Arc::new(S3Storage::from_env().await.context("failed to initialize s3 client")?)
+            }
+            StorageKind::Fs => Arc::new(
+                FsStorage::new(&config.storage_fs_root)
+                    .await
+                    .context("failed to initialize fs storage")?,
+            ),
+        };
+        sweeper::spawn(
+            db_pool.clone(),
+            storage.clone(),
+            Duration::from_secs(config.multipart_upload_ttl_secs),
+            Duration::from_secs(config.multipart_sweep_interval_secs),
+            config.multipart_sweep_max_attempts,
+        );
+        let indexer: Arc<dyn Indexer> = match config.indexer {
+            IndexerKind::Clickhouse => Arc::new(
+                ClickhouseIndexer::connect(
+                    config.clickhouse_batch_max_rows,
+                    Duration::from_millis(config.clickhouse_batch_period_ms),
+                )
+                .await
+                .context("failed to initialize clickhouse indexer")?,
+            ),
+            IndexerKind::Sqlite => Arc::new(
+                SqliteIndexer::new(db_pool.clone())
+                    .await
+                    .context("failed to initialize sqlite indexer")?,
+            ),
+            IndexerKind::Disabled => Arc::new(NoopIndexer),
+        };
+        let access = Arc::new(
+            AccessPolicy::load(db_pool.clone())
+                .await
+                .context("failed to load owner access policy")?,
+        );
+        access.spawn_refresh(Duration::from_secs(config.access_refresh_secs));
+        let quotas = Arc::new(
+            Quotas::new(
+                db_pool.clone(),
+                config.enforce_free_limit.then_some(config.free_upload_limit_bytes.into()),
+                OwnerQuota {
+                    daily_bytes: config.default_daily_quota_bytes,
+                    total_bytes: config.default_total_quota_bytes,
+                },
+            )
+            .await
+            .context("failed to initialize quotas")?,
+        );
+
+        let graphql = build_schema(indexer.clone());
+        let (finalize, finalize_jobs) = FinalizeQueue::new();
+
+        let state = Self {
+            db_pool,
+            storage,
+            indexer,
+            access,
+            quotas,
+            receipt_signer,
+            graphql,
+            finalize,
+            config: Arc::new(config),
+        };
+        finalizer::spawn_workers(state.clone(), finalize_jobs, state.config.finalize_workers);
+        finalizer::recover(&state).await.context("failed to resume interrupted finalizations")?;
+        finalizer::spawn_stuck_sweep(
+            state.clone(),
+            Duration::from_secs(state.config.finalize_timeout_secs),
+            Duration::from_secs(state.config.multipart_sweep_interval_secs),
+        );
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State over an in-memory database, `storage` and the SQLite indexer, for tests. No
+    /// background task runs, finalize jobs wait on the returned receiver.
+    pub(crate) async fn for_tests(
+        storage: Arc<dyn StorageBackend>,
+    ) -> (Self, tokio::sync::mpsc::Receiver<finalizer::FinalizeJob>) {
+        let config = Config::default();
+        let db_pool = crate::db::test_pool().await;
+        let indexer: Arc<dyn Indexer> =
+            Arc::new(SqliteIndexer::new(db_pool.clone()).await.unwrap());
+        let quotas = Quotas::new(
+            db_pool.clone(),
+            config.enforce_free_limit.then_some(config.free_upload_limit_bytes.into()),
+            OwnerQuota {
+                daily_bytes: config.default_daily_quota_bytes,
+                total_bytes: config.default_total_quota_bytes,
+            },
+        )
+        .await
+        .unwrap();
+        let (finalize, finalize_jobs) = FinalizeQueue::new();
+        let state = Self {
+            access: Arc::new(AccessPolicy::load(db_pool.clone()).await.unwrap()),
+            quotas: Arc::new(quotas),
+            receipt_signer: Arc::new(
+                ReceiptSigner::from_jwk_str(include_str!(
+                    "../arbundles/testdata/arweave_wallet.json"
+                ))
+                .unwrap(),
+            ),
+            graphql: build_schema(indexer.clone()),
+            db_pool,
+            storage,
+            indexer,
+            finalize,
+            config: Arc::new(config),
+        };
+        (state, finalize_jobs)
+    }
+
+    /// Upload `dataitem` as a multipart session of `parts` chunks and request its finalize,
+    /// as a client would.
+    pub(crate) async fn finalize_requested(
+        &self,
+        upload_id: &str,
+        token: crate::arbundles::token::Token,
+        dataitem: &[u8],
+        parts: usize,
+    ) {
+        use crate::db::{create_upload_record, request_finalize, save_chunk};
+
+        let chunk_size = dataitem.len().div_ceil(parts);
+        let upload_key = format!("multipart-{upload_id}");
+        let s3_upload_id = self.storage.create_multipart(&upload_key, None).await.unwrap();
+        create_upload_record(
+            &self.db_pool,
+            upload_id,
+            &upload_key,
+            &s3_upload_id,
+            chunk_size as i64,
+        )
+        .await
+        .unwrap();
+        for (index, chunk) in dataitem.chunks(chunk_size).enumerate() {
+            let part_number = index as i32 + 1;
+            let e_tag = self
+                .storage
+                .upload_part(&upload_key, &s3_upload_id, part_number, chunk.to_vec())
+                .await
+                .unwrap();
+            save_chunk(&self.db_pool, upload_id, part_number.into(), &e_tag, chunk.len() as i64)
+                .await
+                .unwrap();
+        }
+        request_finalize(&self.db_pool, upload_id, token.as_str()).await.unwrap();
+    }
+}
+
+impl FromRef<AppState> for SqlitePool {
+    fn from_ref(state: &AppState) -> Self {
+        state.db_pool.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<dyn StorageBackend> {
+    fn from_ref(state: &AppState) -> Self {
+        state.storage.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<dyn Indexer> {
+    fn from_ref(state: &AppState) -> Self {
+        state.indexer.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<AccessPolicy> {
+    fn from_ref(state: &AppState) -> Self {
+        state.access.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Quotas> {
+    fn from_ref(state: &AppState) -> Self {
+        state.quotas.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<ReceiptSigner> {
+    fn from_ref(state: &AppState) -> Self {
+        state.receipt_signer.clone()
+    }
+}
+
+impl FromRef<AppState> for GatewaySchema {
+    fn from_ref(state: &AppState) -> Self {
+        state.graphql.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
@@ -0,0 +1,109 @@
+use crate::storage::{ObjectStream, StorageBackend, StoredPart, fs::FsStorage};
+
+use anyhow::{Error, bail};
+use async_trait::async_trait;
+use std::sync::Mutex;
+
+/// Filesystem backend whose next call to a chosen operation fails, for tests of what a
+/// failing backend leaves behind.
+pub(crate) struct FlakyStorage {
+    inner: FsStorage,
+    failing: Mutex<Option<&'static str>>,
+}
+
+impl FlakyStorage {
+    pub(crate) fn new(inner: FsStorage) -> Self {
+        Self { inner, failing: Mutex::new(None) }
+    }
+
+    /// fail the next call of the `operation` method, named as in [`StorageBackend`]
+    pub(crate) fn fail_next(&self, operation: &'static str) {
+        *self.failing.lock().unwrap() = Some(operation);
+    }
+
+    fn call(&self, operation: &'static str) -> Result<(), Error> {
+        let mut failing = self.failing.lock().unwrap();
+        if *failing == Some(operation) {
+            *failing = None;
+            bail!("{operation} failed");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for FlakyStorage {
+    fn dataitem_key(&self, dataitem_id: &str) -> String {
+        self.inner.dataitem_key(dataitem_id)
+    }
+
+    async fn put_object(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), Error> {
+        self.call("put_object")?;
+        self.inner.put_object(key, body, content_type).await
+    }
+
+    async fn head_object(&self, key: &str) -> Result<Option<u64>, Error> {
+        self.call("head_object")?;
+        self.inner.head_object(key).await
+    }
+
+    async fn get_object_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<ObjectStream, Error> {
+        self.call("get_object_range")?;
+        self.inner.get_object_range(key, start, end).await
+    }
+
+    async fn create_multipart(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+    ) -> Result<String, Error> {
+        self.call("create_multipart")?;
+        self.inner.create_multipart(key, content_type).await
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        body: Vec<u8>,
+    ) -> Result<String, Error> {
+        self.call("upload_part")?;
+        self.inner.upload_part(key, upload_id, part_number, body).await
+    }
+
+    async fn list_parts(&self, key: &str, upload_id: &str) -> Result<Vec<StoredPart>, Error> {
+        self.call("list_parts")?;
+        self.inner.list_parts(key, upload_id).await
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<StoredPart>,
+    ) -> Result<(), Error> {
+        self.call("complete_multipart")?;
+        self.inner.complete_multipart(key, upload_id, parts).await
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<(), Error> {
+        self.call("abort_multipart")?;
+        self.inner.abort_multipart(key, upload_id).await
+    }
+
+    async fn copy_object(&self, from: &str, to: &str, content_type: &str) -> Result<(), Error> {
+        self.call("copy_object")?;
+        self.inner.copy_object(from, to, content_type).await
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), Error> {
+        self.call("delete_object")?;
+        self.inner.delete_object(key).await
+    }
+}
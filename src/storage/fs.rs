@@ -0,0 +1,294 @@
+use crate::{
+    metrics::METRICS,
+    storage::{ObjectStream, StorageBackend, StoredPart, UploadNotFound},
+};
+
+use anyhow::{Error, anyhow};
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use std::{
+    io::SeekFrom,
+    path::{Component, Path, PathBuf},
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+const DATAITEMS_DIR: &str = "dataitems";
+// in-flight multipart uploads, one directory of numbered parts per upload
+const MULTIPART_DIR: &str = ".multipart";
+
+/// Local filesystem backend: objects are plain files under `root`, keyed by their relative
+/// path. Meant for single-node deployments, local development and integration tests.
+#[derive(Clone)]
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    pub async fn new(root: impl Into<PathBuf>) -> Result<Self, Error> {
+        let root = root.into();
+        fs::create_dir_all(root.join(MULTIPART_DIR)).await?;
+        Ok(Self { root })
+    }
+
+    fn object_path(&self, key: &str) -> Result<PathBuf, Error> {
+        let relative = Path::new(key);
+        if key.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(anyhow!("invalid object key {key}"));
+        }
+        Ok(self.root.join(relative))
+    }
+
+    fn upload_dir(&self, upload_id: &str) -> Result<PathBuf, Error> {
+        Uuid::parse_str(upload_id).map_err(|_| anyhow!("invalid upload id {upload_id}"))?;
+        Ok(self.root.join(MULTIPART_DIR).join(upload_id))
+    }
+
+    // write to a sibling temp file first so readers never see a partial object
+    async fn write_atomic(&self, path: &Path, body: &[u8]) -> Result<(), Error> {
+        let tmp_path = self.tmp_sibling(path).await?;
+        fs::write(&tmp_path, body).await?;
+        fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+
+    async fn tmp_sibling(&self, path: &Path) -> Result<PathBuf, Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        Ok(path.with_extension(format!("tmp-{}", Uuid::new_v4())))
+    }
+}
+
+// parts are never served back, an etag only has to tell them apart
+fn part_e_tag(part_number: i32, size: u64) -> String {
+    format!("{part_number}-{size}")
+}
+
+#[async_trait]
+impl StorageBackend for FsStorage {
+    fn dataitem_key(&self, dataitem_id: &str) -> String {
+        format!("{DATAITEMS_DIR}/{dataitem_id}.ans104")
+    }
+
+    async fn put_object(&self, key: &str, body: Vec<u8>, _content_type: &str) -> Result<(), Error> {
+        let _timer = METRICS.backend("fs", "put_object").start_timer();
+        let path = self.object_path(key)?;
+        self.write_atomic(&path, &body).await
+    }
+
+    async fn head_object(&self, key: &str) -> Result<Option<u64>, Error> {
+        let _timer = METRICS.backend("fs", "head_object").start_timer();
+        match fs::metadata(self.object_path(key)?).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_object_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<ObjectStream, Error> {
+        let _timer = METRICS.backend("fs", "get_object_range").start_timer();
+        let mut file = fs::File::open(self.object_path(key)?).await?;
+        let size = file.metadata().await?.len();
+        let end = end.map_or(size, |end| (end + 1).min(size));
+
+        file.seek(SeekFrom::Start(start)).await?;
+        let reader = file.take(end.saturating_sub(start));
+
+        Ok(ReaderStream::new(reader).map_err(Error::from).boxed())
+    }
+
+    async fn create_multipart(
+        &self,
+        _key: &str,
+        _content_type: Option<&str>,
+    ) -> Result<String, Error> {
+        let _timer = METRICS.backend("fs", "create_multipart").start_timer();
+        let upload_id = Uuid::new_v4().to_string();
+        fs::create_dir_all(self.upload_dir(&upload_id)?).await?;
+        Ok(upload_id)
+    }
+
+    async fn upload_part(
+        &self,
+        _key: &str,
+        upload_id: &str,
+        part_number: i32,
+        body: Vec<u8>,
+    ) -> Result<String, Error> {
+        let _timer = METRICS.backend("fs", "upload_part").start_timer();
+        let upload_dir = self.upload_dir(upload_id)?;
+        if !fs::try_exists(&upload_dir).await? {
+            return Err(anyhow!("no such multipart upload {upload_id}"));
+        }
+
+        self.write_atomic(&upload_dir.join(part_number.to_string()), &body).await?;
+        Ok(part_e_tag(part_number, body.len() as u64))
+    }
+
+    async fn list_parts(&self, _key: &str, upload_id: &str) -> Result<Vec<StoredPart>, Error> {
+        let _timer = METRICS.backend("fs", "list_parts").start_timer();
+        let mut entries = fs::read_dir(self.upload_dir(upload_id)?).await?;
+        let mut parts = Vec::new();
+
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(part_number) =
+                entry.file_name().to_str().and_then(|name| name.parse::<i32>().ok())
+            else {
+                continue;
+            };
+            let size = entry.metadata().await?.len();
+            parts.push(StoredPart { part_number, e_tag: part_e_tag(part_number, size) });
+        }
+
+        parts.sort_by_key(|part| part.part_number);
+        Ok(parts)
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        mut parts: Vec<StoredPart>,
+    ) -> Result<(), Error> {
+        let _timer = METRICS.backend("fs", "complete_multipart").start_timer();
+        let upload_dir = self.upload_dir(upload_id)?;
+        let path = self.object_path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        parts.sort_by_key(|part| part.part_number);
+
+        let tmp_path = upload_dir.join("assembled");
+        let mut assembled = fs::File::create(&tmp_path).await?;
+        for part in parts {
+            let mut part_file =
+                fs::File::open(upload_dir.join(part.part_number.to_string())).await?;
+            tokio::io::copy(&mut part_file, &mut assembled).await?;
+        }
+        assembled.flush().await?;
+        drop(assembled);
+
+        fs::rename(&tmp_path, &path).await?;
+        fs::remove_dir_all(&upload_dir).await?;
+        Ok(())
+    }
+
+    async fn abort_multipart(&self, _key: &str, upload_id: &str) -> Result<(), Error> {
+        let _timer = METRICS.backend("fs", "abort_multipart").start_timer();
+        match fs::remove_dir_all(self.upload_dir(upload_id)?).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(UploadNotFound(upload_id.to_string()).into())
+            }
+            Err(e) => Err(e.into()),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    async fn copy_object(&self, from: &str, to: &str, _content_type: &str) -> Result<(), Error> {
+        let _timer = METRICS.backend("fs", "copy_object").start_timer();
+        let to = self.object_path(to)?;
+        let tmp_path = self.tmp_sibling(&to).await?;
+        fs::copy(self.object_path(from)?, &tmp_path).await?;
+        fs::rename(&tmp_path, to).await?;
+        Ok(())
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), Error> {
+        let _timer = METRICS.backend("fs", "delete_object").start_timer();
+        match fs::remove_file(self.object_path(key)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn storage() -> (FsStorage, tempfile::TempDir) {
+        let root = tempfile::tempdir().unwrap();
+        (FsStorage::new(root.path()).await.unwrap(), root)
+    }
+
+    async fn read(storage: &FsStorage, key: &str) -> Vec<u8> {
+        let chunks: Vec<_> =
+            storage.get_object_range(key, 0, None).await.unwrap().try_collect().await.unwrap();
+        chunks.concat()
+    }
+
+    // what the directory of `path` holds, temp files included
+    fn dir_entries(path: &Path) -> Vec<String> {
+        let mut entries: Vec<_> = std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    #[tokio::test]
+    async fn object_keys_stay_under_the_root() {
+        let (storage, root) = storage().await;
+        assert_eq!(
+            storage.object_path("dataitems/a.ans104").unwrap(),
+            root.path().join("dataitems/a.ans104")
+        );
+        for key in ["", "../a", "dataitems/../../a", "/etc/passwd", "./a", "a/.."] {
+            assert!(storage.object_path(key).is_err(), "{key}");
+            assert!(storage.head_object(key).await.is_err(), "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_ids_are_uuids() {
+        let (storage, root) = storage().await;
+        let upload_id = Uuid::new_v4().to_string();
+        assert_eq!(
+            storage.upload_dir(&upload_id).unwrap(),
+            root.path().join(MULTIPART_DIR).join(&upload_id)
+        );
+        for upload_id in ["", "not-a-uuid", "..", "../dataitems", "a/b"] {
+            assert!(storage.upload_dir(upload_id).is_err(), "{upload_id}");
+            assert!(storage.upload_part("key", upload_id, 1, vec![1]).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_replace_objects_whole() {
+        let (storage, root) = storage().await;
+        storage.put_object("dataitems/a", vec![1; 100], "").await.unwrap();
+        storage.put_object("dataitems/a", vec![2; 10], "").await.unwrap();
+        assert_eq!(read(&storage, "dataitems/a").await, vec![2; 10]);
+
+        storage.put_object("b", vec![3; 50], "").await.unwrap();
+        storage.copy_object("b", "dataitems/a", "").await.unwrap();
+        assert_eq!(read(&storage, "dataitems/a").await, vec![3; 50]);
+
+        let upload_id = storage.create_multipart("dataitems/c", None).await.unwrap();
+        for part_number in [2, 1] {
+            storage
+                .upload_part("dataitems/c", &upload_id, part_number, vec![part_number as u8; 5])
+                .await
+                .unwrap();
+        }
+        let parts = storage.list_parts("dataitems/c", &upload_id).await.unwrap();
+        storage.complete_multipart("dataitems/c", &upload_id, parts).await.unwrap();
+        assert_eq!(read(&storage, "dataitems/c").await, [[1; 5], [2; 5]].concat());
+
+        // no temp file or upload left behind
+        assert_eq!(dir_entries(&root.path().join("dataitems")), ["a", "c"]);
+        assert!(dir_entries(&root.path().join(MULTIPART_DIR)).is_empty());
+    }
+}
@@ -0,0 +1,787 @@
+#[cfg(test)]
+pub(crate) mod flaky;
+pub mod fs;
+pub mod s3;
+
+use crate::{
+    access::OwnerNotAllowed,
+    arbundles::{
+        deep_hash::deep_hash_blob_digest,
+        token::Token,
+        verify::{InvalidDataItem, dataitem_signature_message, verify_dataitem_signature},
+    },
+    db::{
+        FinalizeStep, UPLOAD_VALIDATING, delete_upload_records, get_completed_upload, get_upload,
+        mark_upload_failed, roll_back_finalize, set_finalize_step, set_upload_status,
+        store_completed_upload,
+    },
+    indexing::IndexedDataItem,
+    quota::{DataItemTooLarge, InsufficientBalance},
+    state::AppState,
+    utils::{
+        DataItemHeader, HEADER_PREFETCH_SIZE, STREAM_PART_SIZE, is_unexpected_eof,
+        parse_dataitem_header, read_dataitem_header,
+    },
+};
+use anyhow::Error;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::{Stream, StreamExt, TryStreamExt, stream::BoxStream};
+use sha2::{Digest, Sha384};
+use std::{fmt, io::Cursor};
+use tracing::warn;
+
+pub type ObjectStream = BoxStream<'static, Result<Bytes, Error>>;
+
+#[derive(Debug, Clone)]
+pub struct StoredPart {
+    pub part_number: i32,
+    pub e_tag: String,
+}
+
+/// Object storage operations the upload service relies on. Dataitems are stored as
+/// ANS-104 serialized objects under [`StorageBackend::dataitem_key`], multipart sessions are
+/// assembled under their own upload key first.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// where a stored dataitem lives, `{dir}/{id}.ans104`
+    fn dataitem_key(&self, dataitem_id: &str) -> String;
+
+    async fn put_object(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), Error>;
+
+    /// the object size, `None` when the key does not exist
+    async fn head_object(&self, key: &str) -> Result<Option<u64>, Error>;
+
+    /// stream the object bytes from `start` up to `end` (inclusive), or to the end of it
+    async fn get_object_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<ObjectStream, Error>;
+
+    async fn create_multipart(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+    ) -> Result<String, Error>;
+
+    /// store one part of a multipart upload, returns its etag
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        body: Vec<u8>,
+    ) -> Result<String, Error>;
+
+    async fn list_parts(&self, key: &str, upload_id: &str) -> Result<Vec<StoredPart>, Error>;
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<StoredPart>,
+    ) -> Result<(), Error>;
+
+    /// fails with [`UploadNotFound`] when storage has no such multipart upload anymore
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<(), Error>;
+
+    async fn copy_object(&self, from: &str, to: &str, content_type: &str) -> Result<(), Error>;
+
+    async fn delete_object(&self, key: &str) -> Result<(), Error>;
+}
+
+/// A multipart upload storage doesn't know, because it was aborted or completed already.
+#[derive(Debug)]
+pub struct UploadNotFound(pub String);
+
+impl fmt::Display for UploadNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "multipart upload {} not found", self.0)
+    }
+}
+
+impl std::error::Error for UploadNotFound {}
+
+/// A dataitem that passed verification and is stored under its dataitem key.
+pub struct StoredDataItem {
+    pub id: String,
+    pub owner: String,
+    pub signature_type: u16,
+    pub size: usize,
+}
+
+/// Stream a signed ANS-104 DataItem into its dataitem key. Only the header and the part
+/// being filled are held in memory: items smaller than one part are stored with a single put,
+/// larger ones through a multipart upload that only gets completed once the signature over
+/// the whole payload checks out. `declared_size`, the request `Content-Length`, is checked
+/// against the owner's quota before any of the payload is stored.
+pub(crate) async fn store_dataitem_stream<S, E>(
+    state: &AppState,
+    token: Token,
+    declared_size: Option<u64>,
+    mut stream: S,
+) -> Result<StoredDataItem, Error>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let AppState { storage, indexer, access, quotas, .. } = state;
+    let storage = storage.as_ref();
+    let (header, mut part) = read_dataitem_header(&mut stream).await?;
+    let owner = header.owner_address();
+    check_token(token, &header)?;
+    access.check(&owner)?;
+    let dataitem_id = header.id();
+    // refuse items the owner's quota can't take before storing any of the payload
+    quotas
+        .check(&owner, Some(&dataitem_id), declared_size.unwrap_or(0).max(part.len() as u64))
+        .await?;
+    let content_type = header.content_type();
+    let key_dataitem = storage.dataitem_key(&dataitem_id);
+
+    let mut data_hasher = Sha384::new();
+    data_hasher.update(&part[header.data_offset..]);
+    let mut dataitem_size = part.len();
+
+    let mut multipart_upload_id: Option<String> = None;
+    let mut completed_parts: Vec<StoredPart> = Vec::new();
+
+    let streamed: Result<(), Error> = async {
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            data_hasher.update(&chunk);
+            dataitem_size += chunk.len();
+            part.extend_from_slice(&chunk);
+
+            if part.len() < STREAM_PART_SIZE {
+                continue;
+            }
+
+            let upload_id = match &multipart_upload_id {
+                Some(upload_id) => upload_id.clone(),
+                None => {
+                    let upload_id =
+                        storage.create_multipart(&key_dataitem, Some(&content_type)).await?;
+                    multipart_upload_id = Some(upload_id.clone());
+                    upload_id
+                }
+            };
+
+            let part_number = completed_parts.len() as i32 + 1;
+            let body = std::mem::take(&mut part);
+            let e_tag = storage.upload_part(&key_dataitem, &upload_id, part_number, body).await?;
+            completed_parts.push(StoredPart { part_number, e_tag });
+        }
+        Ok(())
+    }
+    .await;
+
+    let verified = streamed.and_then(|_| {
+        let data_len = (dataitem_size - header.data_offset) as u64;
+        let data_hash = deep_hash_blob_digest(data_len, &data_hasher.finalize());
+        verify_dataitem_signature(&header, dataitem_signature_message(&header, data_hash))
+    });
+
+    match multipart_upload_id {
+        // small dataitem, still entirely in the current part
+        None => {
+            verified?;
+            quotas.check(&owner, Some(&dataitem_id), dataitem_size as u64).await?;
+            storage.put_object(&key_dataitem, part, &content_type).await?;
+        }
+        Some(upload_id) => {
+            let completed: Result<(), Error> = async {
+                verified?;
+                quotas.check(&owner, Some(&dataitem_id), dataitem_size as u64).await?;
+                if !part.is_empty() {
+                    let part_number = completed_parts.len() as i32 + 1;
+                    let e_tag =
+                        storage.upload_part(&key_dataitem, &upload_id, part_number, part).await?;
+                    completed_parts.push(StoredPart { part_number, e_tag });
+                }
+                storage.complete_multipart(&key_dataitem, &upload_id, completed_parts).await
+            }
+            .await;
+
+            if let Err(e) = completed {
+                // nothing becomes visible under the final key, just drop the stored parts
+                if let Err(abort_err) = storage.abort_multipart(&key_dataitem, &upload_id).await {
+                    warn!(key = key_dataitem, error = ?abort_err, "aborting multipart upload failed");
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    indexer
+        .index_dataitem(&IndexedDataItem {
+            id: &dataitem_id,
+            content_type: &content_type,
+            tags: &header.tags_for_index(),
+            size: dataitem_size,
+            owner: Some(&owner),
+            owner_key: Some(&header.owner_key()),
+            signature: Some(&header.encoded_signature()),
+            anchor: header.encoded_anchor().as_deref(),
+            target: header.target_address().as_deref(),
+            token,
+        })
+        .await?;
+
+    quotas.record(&owner, &dataitem_id, dataitem_size as u64).await?;
+
+    Ok(StoredDataItem {
+        id: dataitem_id,
+        owner,
+        signature_type: header.signature_type,
+        size: dataitem_size,
+    })
+}
+
+/// simple dataitem existence check against its content length being non-zero
+pub(crate) async fn does_dataitem_exist(
+    storage: &dyn StorageBackend,
+    dataitem_id: &str,
+) -> Result<bool, Error> {
+    let key_dataitem = storage.dataitem_key(dataitem_id);
+
+    Ok(storage.head_object(&key_dataitem).await?.is_some_and(|size| size > 0))
+}
+
+/// The header and object size of a stored dataitem, `None` when there is no such dataitem.
+pub(crate) async fn load_dataitem_header(
+    storage: &dyn StorageBackend,
+    dataitem_id: &str,
+) -> Result<Option<(DataItemHeader, usize)>, Error> {
+    let key_dataitem = storage.dataitem_key(dataitem_id);
+    let Some(object_size) = storage.head_object(&key_dataitem).await? else {
+        return Ok(None);
+    };
+    let header = read_object_header(storage, &key_dataitem, object_size as usize).await?;
+    Ok(Some((header, object_size as usize)))
+}
+
+/// Parse the ANS-104 header of a stored object with ranged reads, growing the range until
+/// the whole header fits, so the data payload is never pulled for it.
+async fn read_object_header(
+    storage: &dyn StorageBackend,
+    key: &str,
+    object_size: usize,
+) -> Result<DataItemHeader, Error> {
+    if object_size == 0 {
+        return Err(Error::new(InvalidDataItem("empty object".to_string())));
+    }
+
+    let mut range_len = HEADER_PREFETCH_SIZE.min(object_size);
+    loop {
+        let prefix = storage
+            .get_object_range(key, 0, Some(range_len as u64 - 1))
+            .await?
+            .try_fold(Vec::with_capacity(range_len), |mut prefix, chunk| async move {
+                prefix.extend_from_slice(&chunk);
+                Ok(prefix)
+            })
+            .await?;
+
+        match parse_dataitem_header(&mut Cursor::new(&prefix)) {
+            Ok(header) => return Ok(header),
+            Err(e) if is_unexpected_eof(&e) && range_len < object_size => {
+                range_len = (range_len * 2).min(object_size);
+            }
+            Err(e) => return Err(Error::new(InvalidDataItem(e.to_string()))),
+        }
+    }
+}
+
+/// Check the DataItem signature by streaming the stored data payload through the deep hash,
+/// one body chunk at a time.
+async fn verify_stored_dataitem(
+    storage: &dyn StorageBackend,
+    key: &str,
+    header: &DataItemHeader,
+    object_size: usize,
+) -> Result<(), Error> {
+    let data_len = object_size.saturating_sub(header.data_offset);
+    let mut data_hasher = Sha384::new();
+
+    if data_len > 0 {
+        let mut body = storage.get_object_range(key, header.data_offset as u64, None).await?;
+        while let Some(chunk) = body.try_next().await? {
+            data_hasher.update(&chunk);
+        }
+    }
+
+    let data_hash = deep_hash_blob_digest(data_len as u64, &data_hasher.finalize());
+    verify_dataitem_signature(header, dataitem_signature_message(header, data_hash))
+}
+
+/// Whether the DataItem itself was refused, as opposed to failing to be stored.
+pub(crate) fn is_rejection(e: &Error) -> bool {
+    e.downcast_ref::<InvalidDataItem>().is_some()
+        || e.downcast_ref::<OwnerNotAllowed>().is_some()
+        || e.downcast_ref::<InsufficientBalance>().is_some()
+        || e.downcast_ref::<DataItemTooLarge>().is_some()
+}
+
+/// The upload route token has to match the wallet type the DataItem was signed with.
+fn check_token(token: Token, header: &DataItemHeader) -> Result<(), Error> {
+    if token.accepts_signature_type(header.signature_type) {
+        return Ok(());
+    }
+    Err(InvalidDataItem(format!(
+        "signature type {} can't be uploaded with token {token}",
+        header.signature_type
+    ))
+    .into())
+}
+
+/// A finalize that ended for good without storing anything, and that retrying can't change:
+/// the session is gone, already failed, or was just rolled back.
+#[derive(Debug)]
+pub struct FinalizeAborted(pub String);
+
+impl fmt::Display for FinalizeAborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "finalize aborted: {}", self.0)
+    }
+}
+
+impl std::error::Error for FinalizeAborted {}
+
+fn is_row_not_found(e: &Error) -> bool {
+    matches!(e.downcast_ref::<sqlx::Error>(), Some(sqlx::Error::RowNotFound))
+}
+
+// the one case a finalize can't resume from, reported as the session failure
+const FINALIZE_INTERRUPTED_REASON: &str =
+    "finalize was interrupted after the assembled upload was dropped, upload it again";
+
+/// Assemble, validate and store a multipart session. Every completed step is recorded on the
+/// upload row, so a finalize stopped by a restart or a failed backend call resumes after the
+/// last one instead of starting over, and each step tolerates having run before.
+pub async fn finalize_multipart_upload(
+    state: &AppState,
+    token: Token,
+    upload_id: &str,
+) -> Result<StoredDataItem, Error> {
+    let AppState { db_pool: pool, storage, indexer, access, quotas, .. } = state;
+    let storage = storage.as_ref();
+    let upload = match get_upload(pool, upload_id).await {
+        Ok(upload) => upload,
+        Err(e) if is_row_not_found(&e) => {
+            return Err(
+                FinalizeAborted(format!("upload {upload_id} is no longer in progress")).into()
+            );
+        }
+        Err(e) => return Err(e),
+    };
+    if let Some(failed_reason) = upload.failed_reason {
+        return Err(
+            FinalizeAborted(format!("upload {upload_id} already failed: {failed_reason}")).into()
+        );
+    }
+    let step = upload.finalize_step;
+
+    if step < Some(FinalizeStep::Assembled) {
+        // the multipart upload may be completed already, only its step not recorded
+        if storage.head_object(&upload.upload_key).await?.is_none() {
+            let parts = storage.list_parts(&upload.upload_key, &upload.s3_upload_id).await?;
+            storage.complete_multipart(&upload.upload_key, &upload.s3_upload_id, parts).await?;
+        }
+        set_finalize_step(pool, upload_id, FinalizeStep::Assembled).await?;
+    }
+    set_upload_status(pool, upload_id, UPLOAD_VALIDATING).await?;
+
+    // once copied the dataitem is read back from its final key, the assembled object may be gone
+    let source_key = if step >= Some(FinalizeStep::Copied) {
+        let (dataitem_id, _) = get_completed_upload(pool, upload_id).await?;
+        storage.dataitem_key(&dataitem_id)
+    } else {
+        upload.upload_key.clone()
+    };
+
+    // size from the object metadata, header through ranged reads
+    let Some(dataitem_size) = storage.head_object(&source_key).await? else {
+        roll_back_finalize(pool, upload_id, FINALIZE_INTERRUPTED_REASON).await?;
+        return Err(FinalizeAborted(format!(
+            "object {source_key} of a finalize in progress is missing, finalize rolled back"
+        ))
+        .into());
+    };
+    let dataitem_size = dataitem_size as usize;
+
+    let header = if step >= Some(FinalizeStep::Validated) {
+        read_object_header(storage, &source_key, dataitem_size).await?
+    } else {
+        let verified = async {
+            let header = read_object_header(storage, &upload.upload_key, dataitem_size).await?;
+            check_token(token, &header)?;
+            let owner = header.owner_address();
+            access.check(&owner)?;
+            quotas.check(&owner, Some(&header.id()), dataitem_size as u64).await?;
+            verify_stored_dataitem(storage, &upload.upload_key, &header, dataitem_size).await?;
+            Ok::<_, Error>(header)
+        }
+        .await;
+
+        match verified {
+            Ok(header) => header,
+            Err(e) => {
+                if is_rejection(&e) {
+                    // the multipart upload is already completed, drop the assembled object
+                    storage.delete_object(&upload.upload_key).await?;
+                    mark_upload_failed(pool, upload_id, &e.to_string()).await?;
+                }
+                return Err(e);
+            }
+        }
+    };
+
+    let dataitem_id = header.id();
+    let content_type = header.content_type();
+    let owner_address = header.owner_address();
+    let target = header.target_address();
+    let tags_for_index = header.tags_for_index();
+
+    if step < Some(FinalizeStep::Validated) {
+        store_completed_upload(pool, upload_id, &dataitem_id, Some(&owner_address)).await?;
+        set_finalize_step(pool, upload_id, FinalizeStep::Validated).await?;
+    }
+
+    if step < Some(FinalizeStep::Copied) {
+        // copy to final location with offchain-dataitems naming standard
+        let final_key = storage.dataitem_key(&dataitem_id);
+        storage.copy_object(&upload.upload_key, &final_key, &content_type).await?;
+        set_finalize_step(pool, upload_id, FinalizeStep::Copied).await?;
+    }
+
+    if step < Some(FinalizeStep::Indexed) {
+        indexer
+            .index_dataitem(&IndexedDataItem {
+                id: &dataitem_id,
+                content_type: &content_type,
+                tags: &tags_for_index,
+                size: dataitem_size,
+                owner: Some(&owner_address),
+                owner_key: Some(&header.owner_key()),
+                signature: Some(&header.encoded_signature()),
+                anchor: header.encoded_anchor().as_deref(),
+                target: target.as_deref(),
+                token,
+            })
+            .await?;
+        set_finalize_step(pool, upload_id, FinalizeStep::Indexed).await?;
+    }
+
+    if step < Some(FinalizeStep::Recorded) {
+        quotas.record(&owner_address, &dataitem_id, dataitem_size as u64).await?;
+        set_finalize_step(pool, upload_id, FinalizeStep::Recorded).await?;
+    }
+
+    // delete temporary multipart object, gone already when resumed past this point
+    storage.delete_object(&upload.upload_key).await?;
+
+    // db cleanups
+    delete_upload_records(pool, upload_id).await?;
+
+    Ok(StoredDataItem {
+        id: dataitem_id,
+        owner: owner_address,
+        signature_type: header.signature_type,
+        size: dataitem_size,
+    })
+}
+
+/// Give up on a finalize that kept failing: drop what it left in storage, best effort, and
+/// mark the session failed so the client sees why. A session whose dataitem was already copied
+/// to its final key is left as is and `false` returned, the object is served from there so
+/// only finishing the remaining steps settles it.
+pub async fn abandon_finalize(
+    state: &AppState,
+    upload_id: &str,
+    failed_reason: &str,
+) -> Result<bool, Error> {
+    let AppState { db_pool: pool, storage, .. } = state;
+    let upload = get_upload(pool, upload_id).await?;
+    if upload.finalize_step >= Some(FinalizeStep::Copied) {
+        return Ok(false);
+    }
+
+    // past assembly there is no multipart upload left to abort
+    let aborted = if upload.finalize_step < Some(FinalizeStep::Assembled) {
+        storage.abort_multipart(&upload.upload_key, &upload.s3_upload_id).await
+    } else {
+        Ok(())
+    };
+    if let Err(e) = aborted {
+        warn!(upload_id, error = ?e, "aborting multipart upload of an abandoned finalize failed");
+    }
+    if let Err(e) = storage.delete_object(&upload.upload_key).await {
+        warn!(upload_id, error = ?e, "deleting assembled object of an abandoned finalize failed");
+    }
+
+    roll_back_finalize(pool, upload_id, failed_reason).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        arbundles::deep_hash::deep_hash_blob,
+        quota::OwnerQuota,
+        storage::{flaky::FlakyStorage, fs::FsStorage},
+    };
+    use ed25519_dalek::Signer;
+    use sqlx::SqlitePool;
+    use std::sync::Arc;
+
+    const ED25519: &[u8] = include_bytes!("../arbundles/testdata/ed25519.ans104");
+
+    struct Fixture {
+        state: AppState,
+        storage: Arc<FlakyStorage>,
+        root: tempfile::TempDir,
+    }
+
+    async fn fixture() -> Fixture {
+        let root = tempfile::tempdir().unwrap();
+        let storage = Arc::new(FlakyStorage::new(FsStorage::new(root.path()).await.unwrap()));
+        let (state, _) = AppState::for_tests(storage.clone()).await;
+        Fixture { state, storage, root }
+    }
+
+    // what makes the next finalize run fail
+    #[derive(Clone, Copy)]
+    enum Fault {
+        Storage(&'static str),
+        // a table the run writes to is missing
+        Table(&'static str),
+    }
+
+    async fn rename_table(pool: &SqlitePool, from: &str, to: &str) {
+        sqlx::query(&format!("ALTER TABLE {from} RENAME TO {to}")).execute(pool).await.unwrap();
+    }
+
+    async fn finalize_with(fixture: &Fixture, upload_id: &str, fault: Fault) -> Error {
+        let pool = &fixture.state.db_pool;
+        match fault {
+            Fault::Storage(operation) => fixture.storage.fail_next(operation),
+            Fault::Table(table) => rename_table(pool, table, "missing").await,
+        }
+        let finalized = finalize_multipart_upload(&fixture.state, Token::Ed25519, upload_id).await;
+        if let Fault::Table(table) = fault {
+            rename_table(pool, "missing", table).await;
+        }
+        finalized.err().expect("finalize should have failed")
+    }
+
+    async fn assert_finalized(fixture: &Fixture, upload_id: &str) -> String {
+        let AppState { db_pool: pool, storage, .. } = &fixture.state;
+        assert!(get_upload(pool, upload_id).await.is_err());
+        let (dataitem_id, _) = get_completed_upload(pool, upload_id).await.unwrap();
+
+        let stored = storage.get_object_range(&storage.dataitem_key(&dataitem_id), 0, None).await;
+        let stored: Vec<Bytes> = stored.unwrap().try_collect().await.unwrap();
+        assert_eq!(stored.concat(), ED25519);
+        assert_eq!(storage.head_object(&format!("multipart-{upload_id}")).await.unwrap(), None);
+
+        for table in ["dataitem_tags", "usage_ledger"] {
+            let rows: i64 =
+                sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table} WHERE dataitem_id = ?"))
+                    .bind(&dataitem_id)
+                    .fetch_one(pool)
+                    .await
+                    .unwrap();
+            assert!(rows > 0, "{dataitem_id} missing from {table}");
+        }
+        dataitem_id
+    }
+
+    // every step a finalize records, with the fault that stops the run right after it
+    const STEPS: [(Option<FinalizeStep>, Fault); 6] = [
+        (None, Fault::Storage("complete_multipart")),
+        (Some(FinalizeStep::Assembled), Fault::Storage("get_object_range")),
+        (Some(FinalizeStep::Validated), Fault::Storage("copy_object")),
+        (Some(FinalizeStep::Copied), Fault::Table("dataitem_tags")),
+        (Some(FinalizeStep::Indexed), Fault::Table("usage_ledger")),
+        (Some(FinalizeStep::Recorded), Fault::Storage("delete_object")),
+    ];
+
+    // a finalize of ED25519 that failed with `step` as its last recorded one
+    async fn finalize_failing_at(fixture: &Fixture, step: Option<FinalizeStep>) {
+        fixture.state.finalize_requested("upload", Token::Ed25519, ED25519, 2).await;
+        for &(_, fault) in STEPS.iter().take_while(|(reached, _)| *reached <= step) {
+            finalize_with(fixture, "upload", fault).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn finalize_resumes_from_every_step() {
+        for (last, &(resumed_step, _)) in STEPS.iter().enumerate() {
+            let fixture = fixture().await;
+            fixture.state.finalize_requested("upload", Token::Ed25519, ED25519, 2).await;
+
+            for &(step, fault) in &STEPS[..=last] {
+                let e = finalize_with(&fixture, "upload", fault).await;
+                assert!(!is_rejection(&e), "{e}");
+                let upload = get_upload(&fixture.state.db_pool, "upload").await.unwrap();
+                assert_eq!(upload.finalize_step, step);
+                assert_eq!(upload.failed_reason, None);
+            }
+
+            let stored = finalize_multipart_upload(&fixture.state, Token::Ed25519, "upload").await;
+            let stored = stored.unwrap_or_else(|e| panic!("resuming from {resumed_step:?}: {e}"));
+            assert_eq!(stored.size, ED25519.len());
+            assert_eq!(assert_finalized(&fixture, "upload").await, stored.id);
+        }
+    }
+
+    #[tokio::test]
+    async fn finalizing_again_is_aborted() {
+        let fixture = fixture().await;
+        fixture.state.finalize_requested("upload", Token::Ed25519, ED25519, 2).await;
+        finalize_multipart_upload(&fixture.state, Token::Ed25519, "upload").await.unwrap();
+
+        let e = finalize_multipart_upload(&fixture.state, Token::Ed25519, "upload").await;
+        assert!(e.err().unwrap().downcast_ref::<FinalizeAborted>().is_some());
+        assert_finalized(&fixture, "upload").await;
+    }
+
+    #[tokio::test]
+    async fn abandoned_finalizes_before_the_copy_are_failed() {
+        for &(step, _) in &STEPS[..3] {
+            let fixture = fixture().await;
+            finalize_failing_at(&fixture, step).await;
+
+            assert!(abandon_finalize(&fixture.state, "upload", "gave up").await.unwrap());
+            let upload = get_upload(&fixture.state.db_pool, "upload").await.unwrap();
+            assert_eq!(upload.status, "FAILED");
+            assert_eq!(upload.failed_reason.as_deref(), Some("gave up"));
+            let storage = &fixture.state.storage;
+            assert_eq!(storage.head_object(&upload.upload_key).await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn copied_dataitems_are_never_abandoned() {
+        for &(step, _) in &STEPS[3..] {
+            let fixture = fixture().await;
+            finalize_failing_at(&fixture, step).await;
+
+            assert!(!abandon_finalize(&fixture.state, "upload", "gave up").await.unwrap());
+            let upload = get_upload(&fixture.state.db_pool, "upload").await.unwrap();
+            assert_eq!((upload.finalize_step, upload.failed_reason), (step, None));
+
+            // the next run settles it
+            finalize_multipart_upload(&fixture.state, Token::Ed25519, "upload").await.unwrap();
+            assert_finalized(&fixture, "upload").await;
+        }
+    }
+
+    // an ed25519 DataItem over `data`, without target, anchor or tags
+    fn signed_dataitem(data: &[u8]) -> Vec<u8> {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let mut dataitem = 2u16.to_le_bytes().to_vec();
+        dataitem.extend([0; 64]);
+        dataitem.extend(key.verifying_key().as_bytes());
+        dataitem.extend([0; 2 + 16]);
+        dataitem.extend(data);
+
+        let header = parse_dataitem_header(&mut Cursor::new(&dataitem)).unwrap();
+        let message = dataitem_signature_message(&header, deep_hash_blob(data));
+        dataitem[2..66].copy_from_slice(&key.sign(&message).to_bytes());
+        dataitem
+    }
+
+    async fn store(
+        fixture: &Fixture,
+        declared_size: Option<u64>,
+        dataitem: &[u8],
+        chunk_size: usize,
+    ) -> Result<StoredDataItem, Error> {
+        let chunks = dataitem
+            .chunks(chunk_size)
+            .map(|chunk| Ok::<_, std::io::Error>(Bytes::copy_from_slice(chunk)));
+        store_dataitem_stream(
+            &fixture.state,
+            Token::Ed25519,
+            declared_size,
+            futures::stream::iter(chunks),
+        )
+        .await
+    }
+
+    async fn stored_bytes(fixture: &Fixture, key: &str) -> Option<Vec<u8>> {
+        let storage = &fixture.state.storage;
+        storage.head_object(key).await.unwrap()?;
+        let chunks: Vec<Bytes> =
+            storage.get_object_range(key, 0, None).await.unwrap().try_collect().await.unwrap();
+        Some(chunks.concat())
+    }
+
+    // multipart uploads storage still holds parts of
+    fn open_multipart_uploads(fixture: &Fixture) -> usize {
+        std::fs::read_dir(fixture.root.path().join(".multipart")).unwrap().count()
+    }
+
+    #[tokio::test]
+    async fn headers_split_across_chunks_are_stored() {
+        let fixture = fixture().await;
+        let stored = store(&fixture, Some(ED25519.len() as u64), ED25519, 1).await.unwrap();
+
+        assert_eq!(stored.size, ED25519.len());
+        let key = fixture.state.storage.dataitem_key(&stored.id);
+        assert_eq!(stored_bytes(&fixture, &key).await.as_deref(), Some(ED25519));
+        let header = parse_dataitem_header(&mut Cursor::new(ED25519)).unwrap();
+        assert_eq!((stored.id, stored.owner), (header.id(), header.owner_address()));
+    }
+
+    #[tokio::test]
+    async fn large_dataitems_are_stored_in_parts() {
+        let fixture = fixture().await;
+        let dataitem = signed_dataitem(&vec![1; STREAM_PART_SIZE * 2 + 10]);
+
+        let stored = store(&fixture, None, &dataitem, 64 * 1024).await.unwrap();
+        let key = fixture.state.storage.dataitem_key(&stored.id);
+        assert_eq!(stored_bytes(&fixture, &key).await, Some(dataitem));
+        assert_eq!(open_multipart_uploads(&fixture), 0);
+    }
+
+    #[tokio::test]
+    async fn declared_sizes_do_not_bypass_quotas() {
+        let fixture = fixture().await;
+        let header = parse_dataitem_header(&mut Cursor::new(ED25519)).unwrap();
+        let quota = OwnerQuota { daily_bytes: None, total_bytes: Some(150) };
+        fixture.state.quotas.set_quota(&header.owner_address(), quota).await.unwrap();
+        let key = fixture.state.storage.dataitem_key(&header.id());
+
+        // refused up front from the declared size, or once the bytes received are counted
+        for declared_size in [Some(10_000), Some(100), None] {
+            let e = store(&fixture, declared_size, ED25519, 64).await.err().unwrap();
+            assert!(e.downcast_ref::<DataItemTooLarge>().is_some(), "{declared_size:?}: {e}");
+            assert_eq!(stored_bytes(&fixture, &key).await, None);
+        }
+
+        // a declared size over the bytes received only matters for the quota
+        fixture.state.quotas.remove_quota(&header.owner_address()).await.unwrap();
+        let stored = store(&fixture, Some(10_000), ED25519, 64).await.unwrap();
+        assert_eq!(stored.size, ED25519.len());
+    }
+
+    #[tokio::test]
+    async fn bad_signatures_abort_the_stored_parts() {
+        let fixture = fixture().await;
+        let mut dataitem = signed_dataitem(&vec![1; STREAM_PART_SIZE * 2 + 10]);
+        *dataitem.last_mut().unwrap() ^= 1;
+        let header = parse_dataitem_header(&mut Cursor::new(&dataitem)).unwrap();
+
+        let e = store(&fixture, None, &dataitem, 64 * 1024).await.err().unwrap();
+        assert!(e.downcast_ref::<InvalidDataItem>().is_some(), "{e}");
+        let key = fixture.state.storage.dataitem_key(&header.id());
+        assert_eq!(stored_bytes(&fixture, &key).await, None);
+        assert_eq!(open_multipart_uploads(&fixture), 0);
+    }
+}
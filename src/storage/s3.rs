@@ -0,0 +1,254 @@
+use crate::{
+    metrics::METRICS,
+    storage::{ObjectStream, StorageBackend, StoredPart, UploadNotFound},
+    utils::get_env_var,
+};
+
+use anyhow::Error;
+use async_trait::async_trait;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_s3::{
+    Client,
+    types::{CompletedMultipartUpload, CompletedPart, MetadataDirective},
+};
+use futures::{StreamExt, stream};
+
+/// S3 connection and the bucket/directory dataitems live in, built once at startup.
+#[derive(Clone)]
+pub struct S3Storage {
+    pub client: Client,
+    pub bucket_name: String,
+    pub dir_name: String,
+}
+
+impl S3Storage {
+    pub async fn from_env() -> Result<Self, Error> {
+        Ok(Self {
+            client: s3_client().await?,
+            bucket_name: get_env_var("S3_BUCKET_NAME")?,
+            dir_name: get_env_var("S3_DIR_NAME")?,
+        })
+    }
+}
+
+/// Initialize the ~s3@1.0 device connection using the aws s3 sdk.
+pub async fn s3_client() -> Result<Client, Error> {
+    let config = aws_config::defaults(BehaviorVersion::latest())
+        .endpoint_url(get_env_var("AWS_ENDPOINT_URL")?)
+        .region(Region::new(get_env_var("AWS_REGION")?))
+        .credentials_provider(aws_sdk_s3::config::Credentials::new(
+            get_env_var("AWS_ACCESS_KEY_ID")?,
+            get_env_var("AWS_SECRET_ACCESS_KEY")?,
+            None,
+            None,
+            "custom",
+        ))
+        .load()
+        .await;
+
+    let s3_config = aws_sdk_s3::config::Builder::from(&config).force_path_style(true).build();
+    Ok(Client::from_conf(s3_config))
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    fn dataitem_key(&self, dataitem_id: &str) -> String {
+        format!("{}/{dataitem_id}.ans104", self.dir_name)
+    }
+
+    async fn put_object(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), Error> {
+        let _timer = METRICS.backend("s3", "put_object").start_timer();
+        self.client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .body(body.into())
+            .content_type(content_type)
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn head_object(&self, key: &str) -> Result<Option<u64>, Error> {
+        let _timer = METRICS.backend("s3", "head_object").start_timer();
+        match self.client.head_object().bucket(&self.bucket_name).key(key).send().await {
+            Ok(res) => Ok(Some(res.content_length().unwrap_or_default().max(0) as u64)),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_object_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<ObjectStream, Error> {
+        let _timer = METRICS.backend("s3", "get_object_range").start_timer();
+        let range = match end {
+            Some(end) => format!("bytes={start}-{end}"),
+            None => format!("bytes={start}-"),
+        };
+
+        let body = self
+            .client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .range(range)
+            .send()
+            .await?
+            .body;
+
+        Ok(stream::try_unfold(body, |mut body| async move {
+            Ok(body.try_next().await?.map(|chunk| (chunk, body)))
+        })
+        .boxed())
+    }
+
+    async fn create_multipart(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+    ) -> Result<String, Error> {
+        let _timer = METRICS.backend("s3", "create_multipart").start_timer();
+        let response = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .set_content_type(content_type.map(str::to_string))
+            .send()
+            .await?;
+
+        Ok(response.upload_id().unwrap_or_default().to_string())
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        body: Vec<u8>,
+    ) -> Result<String, Error> {
+        let _timer = METRICS.backend("s3", "upload_part").start_timer();
+        let response = self
+            .client
+            .upload_part()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(body.into())
+            .send()
+            .await?;
+
+        Ok(response.e_tag().unwrap_or_default().to_string())
+    }
+
+    async fn list_parts(&self, key: &str, upload_id: &str) -> Result<Vec<StoredPart>, Error> {
+        let _timer = METRICS.backend("s3", "list_parts").start_timer();
+        let mut parts = Vec::new();
+        let mut part_number_marker: Option<String> = None;
+
+        // list_parts pages at 1000 parts
+        loop {
+            let response = self
+                .client
+                .list_parts()
+                .bucket(&self.bucket_name)
+                .key(key)
+                .upload_id(upload_id)
+                .set_part_number_marker(part_number_marker.take())
+                .send()
+                .await?;
+
+            parts.extend(response.parts().iter().map(|part| StoredPart {
+                part_number: part.part_number().unwrap_or_default(),
+                e_tag: part.e_tag().unwrap_or_default().to_string(),
+            }));
+
+            match response.next_part_number_marker() {
+                Some(marker) if response.is_truncated().unwrap_or_default() => {
+                    part_number_marker = Some(marker.to_string());
+                }
+                _ => break,
+            }
+        }
+
+        Ok(parts)
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<StoredPart>,
+    ) -> Result<(), Error> {
+        let _timer = METRICS.backend("s3", "complete_multipart").start_timer();
+        let parts = parts
+            .into_iter()
+            .map(|part| {
+                CompletedPart::builder().e_tag(part.e_tag).part_number(part.part_number).build()
+            })
+            .collect();
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<(), Error> {
+        let _timer = METRICS.backend("s3", "abort_multipart").start_timer();
+        match self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await
+        {
+            Ok(_) => Ok(()),
+            // some S3 compatible stores answer a bare 404 instead of NoSuchUpload
+            Err(e)
+                if e.as_service_error().is_some_and(|e| e.is_no_such_upload())
+                    || e.raw_response().is_some_and(|res| res.status().as_u16() == 404) =>
+            {
+                Err(UploadNotFound(upload_id.to_string()).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn copy_object(&self, from: &str, to: &str, content_type: &str) -> Result<(), Error> {
+        let _timer = METRICS.backend("s3", "copy_object").start_timer();
+        self.client
+            .copy_object()
+            .bucket(&self.bucket_name)
+            .copy_source(format!("{}/{from}", self.bucket_name))
+            .key(to)
+            // S3 keeps the source metadata on a copy unless told to replace it
+            .metadata_directive(MetadataDirective::Replace)
+            .content_type(content_type)
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), Error> {
+        let _timer = METRICS.backend("s3", "delete_object").start_timer();
+        self.client.delete_object().bucket(&self.bucket_name).key(key).send().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+use crate::{
+    db::{fail_upload, find_idle_uploads, record_abort_attempt},
+    metrics::METRICS,
+    storage::{StorageBackend, UploadNotFound},
+};
+use anyhow::Error;
+use sqlx::SqlitePool;
+use std::{sync::Arc, time::Duration};
+use tracing::{error, info, warn};
+
+/// What one sweep aborted and freed.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SweepReport {
+    pub sessions: u64,
+    pub parts: u64,
+    pub freed_bytes: u64,
+}
+
+/// Abort the multipart sessions that got no chunk for `ttl`: the storage multipart upload is
+/// aborted, dropping its parts, the session is marked failed and its chunk rows deleted.
+/// A session storage no longer knows is marked failed the same way, with nothing to free.
+/// One whose abort fails is retried on the next sweep, and marked failed once `max_attempts`
+/// aborts failed.
+pub async fn sweep_abandoned_uploads(
+    pool: &SqlitePool,
+    storage: &dyn StorageBackend,
+    ttl: Duration,
+    max_attempts: u32,
+) -> Result<SweepReport, Error> {
+    let idle_before = chrono::Utc::now().timestamp() - ttl.as_secs() as i64;
+    let failed_reason = format!("upload expired after {}s without a new chunk", ttl.as_secs());
+
+    let mut report = SweepReport::default();
+    for upload in find_idle_uploads(pool, idle_before).await? {
+        let freed = match storage.abort_multipart(&upload.upload_key, &upload.s3_upload_id).await {
+            Ok(()) => true,
+            // aborted or completed some other way, there is nothing left to free
+            Err(e) if e.downcast_ref::<UploadNotFound>().is_some() => {
+                warn!(
+                    upload_id = upload.upload_id,
+                    "abandoned multipart upload is gone from storage"
+                );
+                false
+            }
+            Err(e) => {
+                let attempts = record_abort_attempt(pool, &upload.upload_id).await?;
+                if attempts < i64::from(max_attempts) {
+                    warn!(
+                        upload_id = upload.upload_id,
+                        error = ?e,
+                        attempts,
+                        "aborting abandoned multipart upload failed"
+                    );
+                    continue;
+                }
+                // the parts may still be stored, but the session stops being swept
+                error!(
+                    upload_id = upload.upload_id,
+                    error = ?e,
+                    attempts,
+                    "aborting abandoned multipart upload failed, giving up"
+                );
+                let reason = format!("{failed_reason}, aborting it failed {attempts} times");
+                fail_upload(pool, &upload.upload_id, &reason).await?;
+                METRICS.multipart_sessions_total.with_label_values(&["failed"]).inc();
+                continue;
+            }
+        };
+        fail_upload(pool, &upload.upload_id, &failed_reason).await?;
+        report.sessions += 1;
+        METRICS.multipart_sessions_total.with_label_values(&["expired"]).inc();
+
+        if freed {
+            let freed_bytes = upload.stored_bytes.max(0) as u64;
+            report.parts += upload.parts.max(0) as u64;
+            report.freed_bytes += freed_bytes;
+            METRICS.multipart_expired_bytes_total.inc_by(freed_bytes);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Sweep every `interval` for as long as the process runs.
+pub fn spawn(
+    pool: SqlitePool,
+    storage: Arc<dyn StorageBackend>,
+    ttl: Duration,
+    interval: Duration,
+    max_attempts: u32,
+) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(interval);
+        loop {
+            tick.tick().await;
+            match sweep_abandoned_uploads(&pool, storage.as_ref(), ttl, max_attempts).await {
+                Ok(report) => info!(
+                    sessions = report.sessions,
+                    parts = report.parts,
+                    freed_bytes = report.freed_bytes,
+                    "swept abandoned multipart uploads"
+                ),
+                Err(e) => error!(error = ?e, "sweeping abandoned multipart uploads failed"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{create_upload_record, get_chunks, get_upload, save_chunk, test_pool},
+        storage::fs::FsStorage,
+    };
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    struct Fixture {
+        pool: SqlitePool,
+        storage: FsStorage,
+        _root: tempfile::TempDir,
+    }
+
+    async fn fixture() -> Fixture {
+        let root = tempfile::tempdir().unwrap();
+        Fixture {
+            pool: test_pool().await,
+            storage: FsStorage::new(root.path()).await.unwrap(),
+            _root: root,
+        }
+    }
+
+    // a session holding `parts` parts of 10 bytes, its last chunk `idle_secs` ago
+    async fn session(fixture: &Fixture, upload_id: &str, parts: i32, idle_secs: i64) -> String {
+        let key = format!("uploads/{upload_id}");
+        let s3_upload_id = fixture.storage.create_multipart(&key, None).await.unwrap();
+        for part_number in 1..=parts {
+            let e_tag = fixture
+                .storage
+                .upload_part(&key, &s3_upload_id, part_number, vec![0; 10])
+                .await
+                .unwrap();
+            save_chunk(&fixture.pool, upload_id, part_number.into(), &e_tag, 10).await.unwrap();
+        }
+        create_upload_record(&fixture.pool, upload_id, &key, &s3_upload_id, 10).await.unwrap();
+        backdate(&fixture.pool, upload_id, idle_secs).await;
+        s3_upload_id
+    }
+
+    async fn backdate(pool: &SqlitePool, upload_id: &str, idle_secs: i64) {
+        sqlx::query("UPDATE uploads SET updated_at = updated_at - ? WHERE upload_id = ?")
+            .bind(idle_secs)
+            .bind(upload_id)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn sweep(fixture: &Fixture, max_attempts: u32) -> SweepReport {
+        sweep_abandoned_uploads(&fixture.pool, &fixture.storage, TTL, max_attempts).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_are_aborted_and_failed() {
+        let fixture = fixture().await;
+        let s3_upload_id = session(&fixture, "expired", 2, 120).await;
+
+        let report = sweep(&fixture, 5).await;
+        assert_eq!((report.sessions, report.parts, report.freed_bytes), (1, 2, 20));
+
+        let upload = get_upload(&fixture.pool, "expired").await.unwrap();
+        assert_eq!(upload.status, "FAILED");
+        assert_eq!(
+            upload.failed_reason.as_deref(),
+            Some("upload expired after 60s without a new chunk")
+        );
+        assert!(get_chunks(&fixture.pool, "expired").await.unwrap().is_empty());
+        // its parts are gone with the multipart upload
+        assert!(fixture.storage.list_parts("uploads/expired", &s3_upload_id).await.is_err());
+
+        // and it is not swept again
+        assert_eq!(sweep(&fixture, 5).await.sessions, 0);
+    }
+
+    #[tokio::test]
+    async fn active_sessions_are_kept() {
+        let fixture = fixture().await;
+        let s3_upload_id = session(&fixture, "active", 1, 30).await;
+
+        let report = sweep(&fixture, 5).await;
+        assert_eq!(report.sessions, 0);
+
+        let upload = get_upload(&fixture.pool, "active").await.unwrap();
+        assert_eq!(upload.status, "ASSEMBLING");
+        assert_eq!(upload.failed_reason, None);
+        assert_eq!(get_chunks(&fixture.pool, "active").await.unwrap().len(), 1);
+        let parts = fixture.storage.list_parts("uploads/active", &s3_upload_id).await.unwrap();
+        assert_eq!(parts.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn failing_aborts_give_up_after_max_attempts() {
+        let fixture = fixture().await;
+        // the fs backend refuses to abort an upload id that is not its own
+        create_upload_record(&fixture.pool, "stuck", "uploads/stuck", "not-a-uuid", 10)
+            .await
+            .unwrap();
+        save_chunk(&fixture.pool, "stuck", 1, "1-10", 10).await.unwrap();
+        backdate(&fixture.pool, "stuck", 120).await;
+
+        let report = sweep(&fixture, 2).await;
+        assert_eq!(report.sessions, 0);
+        let upload = get_upload(&fixture.pool, "stuck").await.unwrap();
+        assert_eq!((upload.status.as_str(), upload.failed_reason), ("ASSEMBLING", None));
+
+        let report = sweep(&fixture, 2).await;
+        assert_eq!(report.sessions, 0);
+        let upload = get_upload(&fixture.pool, "stuck").await.unwrap();
+        assert_eq!(upload.status, "FAILED");
+        assert_eq!(
+            upload.failed_reason.as_deref(),
+            Some("upload expired after 60s without a new chunk, aborting it failed 2 times")
+        );
+        assert!(get_chunks(&fixture.pool, "stuck").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sessions_gone_from_storage_are_failed() {
+        let fixture = fixture().await;
+        let s3_upload_id = session(&fixture, "gone", 1, 120).await;
+        fixture.storage.abort_multipart("uploads/gone", &s3_upload_id).await.unwrap();
+
+        let report = sweep(&fixture, 5).await;
+        assert_eq!((report.sessions, report.parts, report.freed_bytes), (1, 0, 0));
+
+        // still there for its status route, with the reason
+        let upload = get_upload(&fixture.pool, "gone").await.unwrap();
+        assert_eq!(upload.status, "FAILED");
+        assert_eq!(
+            upload.failed_reason.as_deref(),
+            Some("upload expired after 60s without a new chunk")
+        );
+        assert!(get_chunks(&fixture.pool, "gone").await.unwrap().is_empty());
+    }
+}
@@ -1,58 +1,67 @@
-use anyhow::Error;
+use crate::arbundles::verify::InvalidDataItem;
+use anyhow::{Context, Error};
+use axum::body::Bytes;
 use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
 use bundles_rs::{
-    ans104::{data_item::DataItem, tags::decode_tags},
+    ans104::tags::{Tag, decode_tags},
     crypto::signer::SignatureType,
 };
 use byteorder::{LittleEndian, ReadBytesExt};
 use dotenvy::dotenv;
+use futures::{Stream, StreamExt};
+use sha2::Sha256;
 use sha3::{Digest, Keccak256};
-use std::{env, io::Read};
+use std::{
+    env,
+    io::{Cursor, ErrorKind, Read},
+};
 
 // constants
-pub(crate) const OBJECT_SIZE_LIMIT: usize = 4 * 1_073_741_824; // 4 GB
-pub(crate) const SERVER_PORT: u32 = 3000;
-pub(crate) const DATA_CACHES: &str = "https://gateway.s3-node-1.load.network";
-pub(crate) const FAST_FINALITY_INDEXES: &str = "https://gateway.s3-node-1.load.network";
-pub(crate) const UPLOADER_AR_ADDRESS: &str = "2BBwe2pSXn_Tp-q_mHry0Obp88dc7L-eDIWx0_BUfD0"; // load-s3-agent address
-pub(crate) const FREE_UPLOAD_LIMIT_BYTES: u32 = 1048576;
-pub(crate) const RECEIPT_VERSION: &str = "0.2.0";
-// ported from https://github.com/ardriveapp/turbo-upload-service/blob/main/src/constants.ts#L298
-pub(crate) const CHUNK_MIN_SIZE: usize = 1024 * 1024 * 5; // 5MiB - AWS minimum
-pub(crate) const CHUNK_MAX_SIZE: usize = 1024 * 1024 * 500; // 500MiB // NOTE: S3 cluster supports upto 5GiB
-pub(crate) const DEFAULT_CHUNK_SIZE: i64 = 25_000_000; // 25MB
-// a 5 years projection based on 2min blocktime,
-// counting from block #1764397
-pub(crate) const RECEIPT_HEIGHT_DEADLINE: u64 = 3_079_297;
+pub(crate) const RECEIPT_VERSION: &str = "0.3.0";
+// receipts signed before every field was covered, still verifiable
+pub(crate) const LEGACY_RECEIPT_VERSION: &str = "0.2.0";
+pub(crate) const DEFAULT_CHUNK_SIZE: i64 = 25_000_000; // 25 MB, not MiB
+// part size when streaming single dataitem uploads to S3
+pub(crate) const STREAM_PART_SIZE: usize = 1024 * 1024 * 5; // 5MiB - AWS minimum
+// ANS-104 caps tags at 128 pairs of (1024 bytes name, 3072 bytes value)
+pub(crate) const MAX_TAGS_BYTES: usize = 128 * (1024 + 3072) + 4096;
+// first ranged read when parsing the header of a stored object, covers most headers
+pub(crate) const HEADER_PREFETCH_SIZE: usize = 64 * 1024;
 
 pub(crate) fn get_env_var(key: &str) -> Result<String, Error> {
     dotenv().ok();
-    Ok(env::var(key)?)
+    env::var(key).with_context(|| format!("{key} env var not set"))
 }
 
-pub(crate) fn extract_owner_address(dataitem: &DataItem) -> String {
-    match dataitem.signature_type {
+fn owner_address(signature_type: &SignatureType, owner: &[u8]) -> String {
+    match signature_type {
         SignatureType::Arweave => {
-            // 512-byte RSA modulus to base64url
-            URL_SAFE_NO_PAD.encode(&dataitem.owner)
+            // sha256 of the 512-byte RSA modulus, base64url like the wallet address
+            URL_SAFE_NO_PAD.encode(Sha256::digest(owner))
         }
         SignatureType::Ed25519 => {
             // 32-byte Ed25519 key to base58
-            bs58::encode(&dataitem.owner).into_string()
+            bs58::encode(owner).into_string()
         }
         SignatureType::Ethereum => {
             // 65-byte uncompressed key to EOA
-            ethereum_address_from_pubkey(&dataitem.owner)
+            ethereum_address_from_pubkey(owner)
         }
         _ => {
             // fallback
-            URL_SAFE_NO_PAD.encode(&dataitem.owner)
+            URL_SAFE_NO_PAD.encode(owner)
         }
     }
 }
 
-pub(crate) fn extract_target(dataitem: &DataItem) -> Option<String> {
-    dataitem.target.map(|target| URL_SAFE_NO_PAD.encode(target))
+/// Wallet address of an Arweave owner stored before addresses were hashed, as the base64url
+/// 512-byte modulus. `None` for anything else.
+pub(crate) fn legacy_arweave_owner_address(stored: &str) -> Option<String> {
+    let owner = URL_SAFE_NO_PAD.decode(stored).ok()?;
+    if owner.len() != SignatureType::Arweave.owner_len() {
+        return None;
+    }
+    Some(owner_address(&SignatureType::Arweave, &owner))
 }
 
 fn ethereum_address_from_pubkey(pubkey: &[u8]) -> String {
@@ -65,23 +74,81 @@ fn ethereum_address_from_pubkey(pubkey: &[u8]) -> String {
     }
 }
 
-pub(crate) fn reconstruct_dataitem_data(data: Vec<u8>) -> Result<(DataItem, String), Error> {
-    let mut cursor = std::io::Cursor::new(&data);
+/// ANS-104 header fields, parsed without touching the data payload.
+#[derive(Debug, Clone)]
+pub(crate) struct DataItemHeader {
+    // raw signature type as found on the wire
+    pub signature_type: u16,
+    pub signature: Vec<u8>,
+    pub owner: Vec<u8>,
+    pub target: Option<[u8; 32]>,
+    pub anchor: Option<Vec<u8>>,
+    pub tags: Vec<Tag>,
+    // avro-encoded tags, exactly as signed
+    pub raw_tags: Vec<u8>,
+    // offset of the first data byte
+    pub data_offset: usize,
+}
+
+impl DataItemHeader {
+    /// the DataItem id, base64url(sha256(signature))
+    pub(crate) fn id(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(&self.signature))
+    }
+
+    pub(crate) fn owner_address(&self) -> String {
+        owner_address(&SignatureType::from_u16(self.signature_type), &self.owner)
+    }
 
+    /// the raw owner public key, base64url
+    pub(crate) fn owner_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(&self.owner)
+    }
+
+    pub(crate) fn encoded_signature(&self) -> String {
+        URL_SAFE_NO_PAD.encode(&self.signature)
+    }
+
+    pub(crate) fn encoded_anchor(&self) -> Option<String> {
+        self.anchor.as_ref().map(|anchor| URL_SAFE_NO_PAD.encode(anchor))
+    }
+
+    pub(crate) fn target_address(&self) -> Option<String> {
+        self.target.map(|target| URL_SAFE_NO_PAD.encode(target))
+    }
+
+    pub(crate) fn tags_for_index(&self) -> Vec<(String, String)> {
+        self.tags.iter().map(|tag| (tag.name.clone(), tag.value.clone())).collect()
+    }
+
+    pub(crate) fn content_type(&self) -> String {
+        self.tags
+            .iter()
+            .find(|tag| tag.name.to_lowercase() == "content-type")
+            .map(|tag| tag.value.clone())
+            .unwrap_or_else(|| "application/octet-stream".to_string())
+    }
+}
+
+pub(crate) fn parse_dataitem_header<R: Read>(reader: &mut R) -> Result<DataItemHeader, Error> {
     // parse signature type and signature
-    let signature_type = SignatureType::from_u16(cursor.read_u16::<LittleEndian>()?);
+    let raw_signature_type = reader.read_u16::<LittleEndian>()?;
+    let signature_type = SignatureType::from_u16(raw_signature_type);
     let mut signature = vec![0u8; signature_type.signature_len()];
-    cursor.read_exact(&mut signature)?;
+    reader.read_exact(&mut signature)?;
 
     // parse owner
     let mut owner = vec![0u8; signature_type.owner_len()];
-    cursor.read_exact(&mut owner)?;
+    reader.read_exact(&mut owner)?;
+
+    let mut data_offset = 2 + signature.len() + owner.len();
 
     // parse target (1 byte presence + 32 bytes if present)
-    let target = match cursor.read_u8()? {
+    let target = match reader.read_u8()? {
         1 => {
             let mut t = [0u8; 32];
-            cursor.read_exact(&mut t)?;
+            reader.read_exact(&mut t)?;
+            data_offset += 32;
             Some(t)
         }
         0 => None,
@@ -89,10 +156,11 @@ pub(crate) fn reconstruct_dataitem_data(data: Vec<u8>) -> Result<(DataItem, Stri
     };
 
     // parse anchor (1 byte presence + 32 bytes if present)
-    let anchor = match cursor.read_u8()? {
+    let anchor = match reader.read_u8()? {
         1 => {
             let mut a = [0u8; 32];
-            cursor.read_exact(&mut a)?;
+            reader.read_exact(&mut a)?;
+            data_offset += 32;
             Some(a.to_vec())
         }
         0 => None,
@@ -100,38 +168,93 @@ pub(crate) fn reconstruct_dataitem_data(data: Vec<u8>) -> Result<(DataItem, Stri
     };
 
     // parse tags
-    let tags_count = cursor.read_u64::<LittleEndian>()? as usize;
-    let tags_bytes_len = cursor.read_u64::<LittleEndian>()? as usize;
+    let tags_count = reader.read_u64::<LittleEndian>()? as usize;
+    let tags_bytes_len = reader.read_u64::<LittleEndian>()? as usize;
+    if tags_bytes_len > MAX_TAGS_BYTES {
+        return Err(anyhow::anyhow!("tags bytes length {tags_bytes_len} exceeds limit"));
+    }
 
-    let mut tags_bytes = vec![0u8; tags_bytes_len];
-    cursor.read_exact(&mut tags_bytes)?;
+    let mut raw_tags = vec![0u8; tags_bytes_len];
+    reader.read_exact(&mut raw_tags)?;
 
-    let tags = decode_tags(&tags_bytes)?;
+    let tags = decode_tags(&raw_tags)?;
     if tags.len() != tags_count {
         return Err(anyhow::anyhow!("tag count mismatch"));
     }
 
-    // parse actual dataitem's data (remaining bytes)
-    let mut data_bytes = Vec::new();
-    cursor.read_to_end(&mut data_bytes)?;
+    // presence bytes + tags count and length
+    data_offset += 2 + 16 + raw_tags.len();
 
-    // create parsed DataItem
-    let dataitem = DataItem {
-        signature_type,
+    Ok(DataItemHeader {
+        signature_type: raw_signature_type,
         signature,
         owner,
         target,
         anchor,
-        tags: tags.clone(),
-        data: data_bytes,
-    };
+        tags,
+        raw_tags,
+        data_offset,
+    })
+}
+
+/// Pull chunks off `stream` until a complete ANS-104 header can be parsed. Returns the
+/// header along with every byte read so far, which starts with the header itself.
+pub(crate) async fn read_dataitem_header<S, E>(
+    stream: &mut S,
+) -> Result<(DataItemHeader, Vec<u8>), Error>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut buf = Vec::new();
+    loop {
+        match parse_dataitem_header(&mut Cursor::new(&buf)) {
+            Ok(header) => return Ok((header, buf)),
+            Err(e) if is_unexpected_eof(&e) => {}
+            Err(e) => return Err(Error::new(InvalidDataItem(e.to_string()))),
+        }
+
+        match stream.next().await {
+            Some(chunk) => buf.extend_from_slice(&chunk?),
+            None => return Err(Error::new(InvalidDataItem("truncated header".to_string()))),
+        }
+    }
+}
+
+pub(crate) fn is_unexpected_eof(e: &Error) -> bool {
+    e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == ErrorKind::UnexpectedEof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // extract content type from tags
-    let content_type_tag = tags
-        .iter()
-        .find(|tag| tag.name.to_lowercase() == "content-type")
-        .map(|tag| tag.value.clone())
-        .unwrap_or_else(|| "application/octet-stream".to_string());
+    // modulus and address of the arweave-rs test wallet
+    const ARWEAVE_OWNER: &str = "pjdss8ZaDfEH6K6U7GeW2nxDqR4IP049fk1fK0lndimbMMVBdPv_hSpm8T8EtBDxrUdi1OHZfMhUixGaut-3nQ4GG9nM249oxhCtxqqNvEXrmQRGqczyLxuh-fKn9Fg--hS9UpazHpfVAFnB5aCfXoNhPuI8oByyFKMKaOVgHNqP5NBEqabiLftZD3W_lsFCPGuzr4Vp0YS7zS2hDYScC2oOMu4rGU1LcMZf39p3153Cq7bS2Xh6Y-vw5pwzFYZdjQxDn8x8BG3fJ6j8TGLXQsbKH1218_HcUJRvMwdpbUQG5nvA2GXVqLqdwp054Lzk9_B_f1lVrmOKuHjTNHq48w";
+    const ARWEAVE_ADDRESS: &str = "ggHWyKn0I_CTtsyyt2OR85sPYz9OvKLd9DYIvRQ2ET4";
 
-    Ok((dataitem, content_type_tag))
+    #[test]
+    fn arweave_owner_address_is_the_modulus_sha256() {
+        let owner = URL_SAFE_NO_PAD.decode(ARWEAVE_OWNER).unwrap();
+        assert_eq!(owner_address(&SignatureType::Arweave, &owner), ARWEAVE_ADDRESS);
+    }
+
+    #[test]
+    fn legacy_arweave_owners_map_to_their_address() {
+        // ANS-104 arweave owners are 4096 bits moduli
+        let owner = [7u8; 512];
+        assert_eq!(
+            legacy_arweave_owner_address(&URL_SAFE_NO_PAD.encode(owner)),
+            Some(owner_address(&SignatureType::Arweave, &owner))
+        );
+        // already an address, an ed25519 or an ethereum one, or a shorter modulus
+        for stored in [
+            ARWEAVE_ADDRESS,
+            ARWEAVE_OWNER,
+            "2kLbLhhGbR3Ygx3ffPpp14NX2UQvELBmnWgTKZZhPUq3",
+            "0x1e42d9d1bbec0a7a2c2f2b1d0d3ae2a5a8c0b9f1",
+        ] {
+            assert_eq!(legacy_arweave_owner_address(stored), None);
+        }
+    }
 }